@@ -0,0 +1,82 @@
+//! Finds the `.hbs` templates under `src/pages` and `src/components`, checks
+//! that they parse, and writes the list that `templates::register_templates`
+//! registers at startup.
+
+use handlebars::Template;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TEMPLATE_DIRS: [&str; 2] = ["src/pages", "src/components"];
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+
+    let mut templates = BTreeMap::new();
+    for dir in TEMPLATE_DIRS {
+        println!("cargo:rerun-if-changed={}", dir);
+        let root = manifest_dir.join(dir);
+        let mut files = Vec::new();
+        find_templates(&root, &mut files);
+
+        for path in files {
+            let name = template_name(&root, &path);
+            let source = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+            if let Err(e) = Template::compile(&source) {
+                panic!("Template {} does not parse: {}", path.display(), e);
+            }
+            if let Some(other) = templates.insert(name.clone(), path.clone()) {
+                panic!(
+                    "{} and {} would both be registered as {}",
+                    other.display(),
+                    path.display(),
+                    name
+                );
+            }
+        }
+    }
+
+    let mut generated = String::from("pub const TEMPLATES: &[TemplateFile] = &[\n");
+    for (name, path) in &templates {
+        let path = path.to_str().expect("template paths must be UTF-8");
+        generated.push_str(&format!(
+            "    TemplateFile {{ name: {:?}, path: {:?}, source: include_str!({:?}) }},\n",
+            name, path, path
+        ));
+    }
+    generated.push_str("];\n");
+
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("templates.rs");
+    fs::write(out, generated).expect("Failed to write template list");
+}
+
+fn find_templates(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries =
+        fs::read_dir(dir).unwrap_or_else(|e| panic!("Failed to read {}: {}", dir.display(), e));
+    for entry in entries {
+        let path = entry.expect("Failed to read directory entry").path();
+        if path.is_dir() {
+            find_templates(&path, files);
+        } else if path.extension().is_some_and(|ext| ext == "hbs") {
+            files.push(path);
+        }
+    }
+}
+
+/// The template's path below its root without the extension, so
+/// `src/pages/book/book_page.hbs` is `book/book_page` and
+/// `src/pages/layout.hbs` is `layout`.
+fn template_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap().with_extension("");
+    relative
+        .components()
+        .map(|c| {
+            c.as_os_str()
+                .to_str()
+                .expect("template paths must be UTF-8")
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
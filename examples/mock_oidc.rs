@@ -0,0 +1,189 @@
+//! A throwaway OpenID Connect provider for trying single sign-on locally.
+//! It signs in whoever you type in, without a password.
+//!
+//! Run it with `cargo run --example mock_oidc` and start the server with
+//!
+//! ```text
+//! OIDC_ISSUER=http://localhost:3001
+//! OIDC_CLIENT_ID=storybook
+//! OIDC_CLIENT_SECRET=mock-secret
+//! OIDC_PROVIDER_NAME="Mock IdP"
+//! ```
+//!
+//! The single sign-on tests in `oidc_client` also run it, on a spare port.
+
+use axum::{
+    extract::{Form, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const ISSUER: &str = "http://localhost:3001";
+const CLIENT_ID: &str = "storybook";
+const CLIENT_SECRET: &str = "mock-secret";
+
+struct Grant {
+    username: String,
+    nonce: String,
+    code_challenge: String,
+    redirect_uri: String,
+}
+
+struct MockState {
+    issuer: String,
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+    code_challenge: String,
+    #[serde(default)]
+    username: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_uri: String,
+    code_verifier: String,
+}
+
+async fn discovery(State(state): State<Arc<MockState>>) -> Json<serde_json::Value> {
+    let issuer = &state.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+        "response_types_supported": ["code"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+        "code_challenge_methods_supported": ["S256"]
+    }))
+}
+
+/// Asks who to sign in as, then sends the browser back with a code.
+async fn authorize(
+    State(state): State<Arc<MockState>>,
+    Query(query): Query<AuthorizeQuery>,
+) -> Response {
+    if query.client_id != CLIENT_ID {
+        return (StatusCode::BAD_REQUEST, "unknown client").into_response();
+    }
+    let Some(username) = query.username.filter(|name| !name.is_empty()) else {
+        return Html(format!(
+            r#"<form method="get" action="/authorize">
+                <input type="hidden" name="client_id" value="{}">
+                <input type="hidden" name="redirect_uri" value="{}">
+                <input type="hidden" name="state" value="{}">
+                <input type="hidden" name="nonce" value="{}">
+                <input type="hidden" name="code_challenge" value="{}">
+                <label>Sign in as <input name="username" autofocus></label>
+                <button>Continue</button>
+            </form>"#,
+            query.client_id, query.redirect_uri, query.state, query.nonce, query.code_challenge
+        ))
+        .into_response();
+    };
+
+    let code = format!(
+        "{:x}",
+        Sha256::digest(format!("{}{}", query.state, username))
+    );
+    state.grants.lock().unwrap().insert(
+        code.clone(),
+        Grant {
+            username,
+            nonce: query.nonce,
+            code_challenge: query.code_challenge,
+            redirect_uri: query.redirect_uri.clone(),
+        },
+    );
+    let location = format!("{}?code={}&state={}", query.redirect_uri, code, query.state);
+    (StatusCode::SEE_OTHER, [(header::LOCATION, location)]).into_response()
+}
+
+async fn token(State(state): State<Arc<MockState>>, Form(form): Form<TokenForm>) -> Response {
+    let Some(grant) = state.grants.lock().unwrap().remove(&form.code) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+            .into_response();
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(form.code_verifier.as_bytes()));
+    if form.client_id != CLIENT_ID
+        || form.client_secret.as_deref() != Some(CLIENT_SECRET)
+        || form.redirect_uri != grant.redirect_uri
+        || challenge != grant.code_challenge
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+            .into_response();
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = json!({
+        "iss": state.issuer,
+        "sub": format!("mock|{}", grant.username),
+        "aud": CLIENT_ID,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": grant.nonce,
+        "email": format!("{}@example.com", grant.username),
+        "email_verified": true,
+        "preferred_username": grant.username
+    });
+    let id_token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+    )
+    .unwrap();
+    Json(json!({ "access_token": "mock", "token_type": "Bearer", "id_token": id_token }))
+        .into_response()
+}
+
+async fn jwks() -> Json<serde_json::Value> {
+    Json(json!({ "keys": [] }))
+}
+
+/// The provider's routes, for serving at `issuer`.
+pub fn router(issuer: &str) -> Router {
+    Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .route("/jwks", get(jwks))
+        .with_state(Arc::new(MockState {
+            issuer: issuer.to_string(),
+            grants: Mutex::default(),
+        }))
+}
+
+#[tokio::main]
+async fn main() {
+    println!("Mock identity provider on {}", ISSUER);
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
+    axum::serve(listener, router(ISSUER)).await.unwrap();
+}
@@ -0,0 +1,210 @@
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{header, request::Parts, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::convert::Infallible;
+
+use crate::services::session_store::random_token;
+
+/// Header HTMX sends the token in, set for the whole page by `hx-headers` on
+/// the layout's `<body>`.
+pub const HEADER: &str = "X-CSRF-Token";
+
+/// The browser's CSRF token, for rendering into the layout. Pages rendered
+/// without it would leave the page's HTMX requests unable to post.
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<CsrfToken>()
+            .cloned()
+            .unwrap_or_else(|| CsrfToken(String::new())))
+    }
+}
+
+/// Double-submit CSRF protection. Every browser gets a random token in the
+/// `csrf` cookie, and every request that is not a GET must echo it back in
+/// the `X-CSRF-Token` header, which a cross-site form post cannot set.
+pub async fn protect(mut request: Request, next: Next) -> Response {
+    let cookie_token = super::cookie(request.headers(), "csrf").map(str::to_string);
+
+    if !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        let header_token = request
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok());
+        let valid = match (&cookie_token, header_token) {
+            (Some(expected), Some(actual)) => tokens_match(expected, actual),
+            _ => false,
+        };
+        if !valid {
+            log::warn!(
+                "Rejected {} {} with a missing or wrong CSRF token",
+                request.method(),
+                request.uri().path()
+            );
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(header::CONTENT_TYPE, "text/html")
+                .body(
+                    "<p role=\"alert\">Your session has expired. Please reload the page and try again.</p>"
+                        .into(),
+                )
+                .unwrap();
+        }
+    }
+
+    let is_new = cookie_token.is_none();
+    let token = cookie_token.unwrap_or_else(random_token);
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+
+    let mut response = next.run(request).await;
+    if is_new {
+        let cookie = format!("csrf={}; Path=/; HttpOnly; SameSite=Strict", token);
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            response.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    response
+}
+
+/// Compares tokens without returning early, so timing does not reveal how
+/// much of a guess was right.
+fn tokens_match(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::any, Router};
+    use reqwest::{Client, Method};
+
+    /// Serves a route behind `protect` that echoes the request's token, and
+    /// returns its URL.
+    async fn protected() -> String {
+        async fn echo(CsrfToken(token): CsrfToken) -> String {
+            token
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/", any(echo))
+            .layer(middleware::from_fn(protect));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn set_cookies(response: &reqwest::Response) -> Vec<&str> {
+        response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn compares_tokens() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+        assert!(!tokens_match("abc123", ""));
+    }
+
+    #[tokio::test]
+    async fn a_post_without_the_header_is_forbidden() {
+        let url = protected().await;
+        let response = Client::new()
+            .post(&url)
+            .header(header::COOKIE, "csrf=secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html");
+        assert!(response
+            .text()
+            .await
+            .unwrap()
+            .starts_with("<p role=\"alert\">"));
+    }
+
+    #[tokio::test]
+    async fn a_post_with_the_wrong_token_is_forbidden() {
+        let url = protected().await;
+        for (cookie, token) in [("csrf=secret", "guess"), ("auth=x", "secret")] {
+            let response = Client::new()
+                .post(&url)
+                .header(header::COOKIE, cookie)
+                .header(HEADER, token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", cookie);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_post_with_the_matching_token_passes() {
+        let url = protected().await;
+        for method in [Method::POST, Method::PUT, Method::DELETE] {
+            let response = Client::new()
+                .request(method.clone(), &url)
+                .header(header::COOKIE, "auth=x; csrf=secret")
+                .header(HEADER, "secret")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", method);
+            assert!(set_cookies(&response).is_empty());
+            assert_eq!(response.text().await.unwrap(), "secret");
+        }
+    }
+
+    #[tokio::test]
+    async fn safe_methods_need_no_token() {
+        let url = protected().await;
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            let response = Client::new()
+                .request(method.clone(), &url)
+                .header(header::COOKIE, "csrf=secret")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_first_visit_gets_a_token_cookie() {
+        let url = protected().await;
+        let response = Client::new().get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookies: Vec<_> = set_cookies(&response)
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        let token = response.text().await.unwrap();
+        assert!(!token.is_empty());
+        assert_eq!(
+            cookies,
+            [format!("csrf={}; Path=/; HttpOnly; SameSite=Strict", token)]
+        );
+    }
+}
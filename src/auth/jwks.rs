@@ -0,0 +1,13 @@
+use axum::{extract::State, routing::get, Json, Router};
+use std::sync::Arc;
+
+use crate::AppState;
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new().route("/.well-known/jwks.json", get(jwks_handler))
+}
+
+/// Publishes our token verification keys for other services.
+pub async fn jwks_handler(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+    Json(state.auth_service.jwks())
+}
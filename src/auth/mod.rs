@@ -0,0 +1,287 @@
+pub mod csrf;
+pub mod jwks;
+pub mod oidc;
+
+use crate::{
+    htmx::HxResponse,
+    models::user::{Claims, Role},
+    services::auth_service::{SessionTokens, TokenLifetimes},
+    AppState,
+};
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{convert::Infallible, marker::PhantomData, sync::Arc};
+
+pub trait RoleRequirement {
+    const ROLE: Role;
+
+    /// Whether a user with `role` meets the requirement.
+    fn allows(role: Role) -> bool {
+        role >= Self::ROLE
+    }
+}
+
+/// Marker types naming the least role a route accepts, used as
+/// `Authorized<require::Author>`.
+pub mod require {
+    use super::RoleRequirement;
+    use crate::models::user::Role;
+
+    pub struct Reader;
+    pub struct Author;
+    pub struct Editor;
+    pub struct Admin;
+
+    impl RoleRequirement for Reader {
+        const ROLE: Role = Role::Reader;
+    }
+
+    impl RoleRequirement for Author {
+        const ROLE: Role = Role::Author;
+    }
+
+    impl RoleRequirement for Editor {
+        const ROLE: Role = Role::Editor;
+    }
+
+    impl RoleRequirement for Admin {
+        const ROLE: Role = Role::Admin;
+    }
+}
+
+/// Reads the `auth` cookie from the request headers.
+pub fn auth_token(headers: &HeaderMap) -> Option<&str> {
+    cookie(headers, "auth")
+}
+
+/// Reads the `refresh` cookie from the request headers.
+pub fn refresh_token(headers: &HeaderMap) -> Option<&str> {
+    cookie(headers, "refresh")
+}
+
+/// A short description of the browser a session was started from, taken
+/// from the `User-Agent` header.
+pub fn device_name(headers: &HeaderMap) -> String {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let browser = ["Edg", "Firefox", "Chrome", "Safari", "curl"]
+        .into_iter()
+        .find(|name| user_agent.contains(&format!("{}/", name)));
+    let platform = ["Android", "iPhone", "iPad", "Windows", "Mac OS", "Linux"]
+        .into_iter()
+        .find(|name| user_agent.contains(name));
+    match (browser, platform) {
+        (Some("Edg"), platform) => describe("Edge", platform),
+        (Some(browser), platform) => describe(browser, platform),
+        (None, _) if user_agent.is_empty() => "Unknown device".to_string(),
+        (None, _) => user_agent.chars().take(60).collect(),
+    }
+}
+
+fn describe(browser: &str, platform: Option<&str>) -> String {
+    match platform {
+        Some(platform) => format!("{} on {}", browser, platform.replace("Mac OS", "macOS")),
+        None => browser.to_string(),
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(header::COOKIE)?
+        .to_str()
+        .ok()?
+        .split(';')
+        .find_map(|s| s.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+/// `Set-Cookie` values for a newly issued or refreshed session.
+pub fn session_cookies(tokens: &SessionTokens, lifetimes: TokenLifetimes) -> Vec<String> {
+    let mut cookies = vec![format!(
+        "auth={}; Path=/; HttpOnly; SameSite=Strict",
+        tokens.access_token
+    )];
+    if let Some(refresh_token) = &tokens.refresh_token {
+        cookies.push(format!(
+            "refresh={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+            refresh_token, lifetimes.refresh_secs
+        ));
+    }
+    cookies
+}
+
+/// `Set-Cookie` values that sign the browser out.
+pub const CLEAR_SESSION_COOKIES: [&str; 2] = [
+    "auth=; Path=/; HttpOnly; Max-Age=0",
+    "refresh=; Path=/; HttpOnly; Max-Age=0",
+];
+
+/// Renews an expired or missing access token from the `refresh` cookie before
+/// the request reaches its handler, so the extractors below see a valid
+/// token and the reader is not bounced out mid-story.
+pub async fn refresh_session(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let access_valid = auth_token(headers)
+        .and_then(|token| state.auth_service.validate_jwt(token))
+        .is_some();
+    let Some(refresh) = refresh_token(headers).filter(|_| !access_valid) else {
+        return next.run(request).await;
+    };
+
+    let Some(tokens) = state.auth_service.refresh_session(refresh) else {
+        let mut response = next.run(request).await;
+        if !sets_session_cookies(&response) {
+            for cookie in CLEAR_SESSION_COOKIES {
+                response
+                    .headers_mut()
+                    .append(header::SET_COOKIE, HeaderValue::from_static(cookie));
+            }
+        }
+        return response;
+    };
+
+    let cookie_header = request
+        .headers()
+        .get(header::COOKIE)
+        .and_then(|cookie| cookie.to_str().ok())
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|s| !s.starts_with("auth="))
+        .chain(std::iter::once(
+            format!("auth={}", tokens.access_token).as_str(),
+        ))
+        .collect::<Vec<_>>()
+        .join("; ");
+    if let Ok(value) = HeaderValue::from_str(&cookie_header) {
+        request.headers_mut().insert(header::COOKIE, value);
+    }
+
+    let mut response = next.run(request).await;
+    if !sets_session_cookies(&response) {
+        for cookie in session_cookies(&tokens, state.auth_service.lifetimes()) {
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+        }
+    }
+    response
+}
+
+/// Whether the handler already signed the browser in or out, in which case
+/// its cookies win over the refreshed ones.
+fn sets_session_cookies(response: &Response) -> bool {
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|cookie| cookie.starts_with("auth=") || cookie.starts_with("refresh="))
+}
+
+/// The claims of the signed-in user, if any. Never rejects a request.
+pub struct MaybeUser(pub Option<Claims>);
+
+impl FromRequestParts<Arc<AppState>> for MaybeUser {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(MaybeUser(
+            auth_token(&parts.headers).and_then(|token| state.auth_service.validate_jwt(token)),
+        ))
+    }
+}
+
+/// The claims of the signed-in user. Visitors who are not signed in are sent
+/// to the configured `LOGIN_REDIRECT`, using `HX-Redirect` for HTMX requests
+/// so the whole page navigates instead of swapping a redirect into a fragment.
+pub struct CurrentUser(pub Claims);
+
+impl FromRequestParts<Arc<AppState>> for CurrentUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let MaybeUser(claims) = MaybeUser::from_request_parts(parts, state).await.unwrap();
+        match claims {
+            Some(claims) => Ok(CurrentUser(claims)),
+            None if parts.headers.contains_key("HX-Request") => Err(HxResponse::new()
+                .redirect(&state.login_redirect)
+                .into_response()),
+            None => Err(Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, &state.login_redirect)
+                .body("Redirecting...".into())
+                .unwrap()),
+        }
+    }
+}
+
+/// The claims of a signed-in user whose role is at least `R`. Users with a
+/// lesser role get a 403; visitors who are not signed in are handled as for
+/// `CurrentUser`.
+pub struct Authorized<R: RoleRequirement> {
+    pub claims: Claims,
+    _role: PhantomData<fn() -> R>,
+}
+
+impl<R: RoleRequirement> FromRequestParts<Arc<AppState>> for Authorized<R> {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let CurrentUser(claims) = CurrentUser::from_request_parts(parts, state).await?;
+
+        if !R::allows(claims.role) {
+            return Err(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(header::CONTENT_TYPE, "text/html")
+                .body("<p role=\"alert\">You do not have permission to do that.</p>".into())
+                .unwrap());
+        }
+
+        Ok(Self {
+            claims,
+            _role: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The requirements each role meets, as (reader, author, editor, admin).
+    fn access(role: Role) -> (bool, bool, bool, bool) {
+        (
+            require::Reader::allows(role),
+            require::Author::allows(role),
+            require::Editor::allows(role),
+            require::Admin::allows(role),
+        )
+    }
+
+    #[test]
+    fn each_role_meets_its_own_requirement_and_those_below() {
+        assert_eq!(access(Role::Reader), (true, false, false, false));
+        assert_eq!(access(Role::Author), (true, true, false, false));
+        assert_eq!(access(Role::Editor), (true, true, true, false));
+        assert_eq!(access(Role::Admin), (true, true, true, true));
+    }
+}
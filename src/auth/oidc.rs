@@ -0,0 +1,166 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use super::{cookie, csrf::CsrfToken, device_name, session_cookies, CurrentUser, MaybeUser};
+use crate::{error::AppError, services::auth_service::now_secs, AppState};
+
+/// Browser cookie holding the `state` of a login in progress, so a callback
+/// only completes the login the same browser started.
+const STATE_COOKIE: &str = "oidc_state";
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/auth/oidc/login", get(oidc_login_handler))
+        .route("/auth/oidc/link", get(oidc_link_handler))
+        .route("/auth/oidc/callback", get(oidc_callback_handler))
+}
+
+/// Sends the browser to the identity provider.
+pub async fn oidc_login_handler(State(state): State<Arc<AppState>>) -> Result<Response, AppError> {
+    redirect_to_provider(&state, None)
+}
+
+/// Sends a signed-in user to the identity provider to link the identity
+/// they sign in with there to their account.
+pub async fn oidc_link_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<Response, AppError> {
+    redirect_to_provider(&state, Some(&claims.sub))
+}
+
+fn redirect_to_provider(state: &AppState, link_to: Option<&str>) -> Result<Response, AppError> {
+    let Some(oidc) = &state.oidc else {
+        return Err(AppError::NotFound("Single sign-on"));
+    };
+
+    let (login_state, url) = oidc.begin(now_secs(), link_to);
+    // Lax rather than Strict, since the provider's redirect back is a
+    // cross-site navigation.
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, url)
+        .header(
+            header::SET_COOKIE,
+            format!(
+                "{}={}; Path=/auth/oidc; HttpOnly; SameSite=Lax; Max-Age=600",
+                STATE_COOKIE, login_state
+            ),
+        )
+        .body("Redirecting...".into())
+        .unwrap())
+}
+
+/// Where the identity provider sends the browser back with a code.
+pub async fn oidc_callback_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    MaybeUser(signed_in): MaybeUser,
+    CsrfToken(csrf_token): CsrfToken,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Response, AppError> {
+    let Some(oidc) = &state.oidc else {
+        return Err(AppError::NotFound("Single sign-on"));
+    };
+    let clear_state_cookie = format!("{}=; Path=/auth/oidc; HttpOnly; Max-Age=0", STATE_COOKIE);
+
+    let error = if let Some(error) = query.error {
+        log::warn!(
+            "Identity provider returned {}: {}",
+            error,
+            query.error_description.as_deref().unwrap_or("")
+        );
+        format!("{} did not sign you in", oidc.provider_name())
+    } else {
+        match (query.code, query.state) {
+            (Some(code), Some(login_state))
+                if cookie(&headers, STATE_COOKIE) == Some(login_state.as_str()) =>
+            {
+                // Linking only goes ahead while the user who asked for it
+                // is still the one signed in.
+                let result = match oidc.finish(&login_state, &code, now_secs()).await {
+                    Ok(login) => match login.link_to {
+                        None => state
+                            .auth_service
+                            .login_with_identity(&login.identity)
+                            .map(Some)
+                            .map_err(|e| e.to_string()),
+                        Some(username)
+                            if signed_in.as_ref().map(|claims| claims.sub.as_str())
+                                == Some(username.as_str()) =>
+                        {
+                            state
+                                .auth_service
+                                .link_identity(&username, &login.identity)
+                                .map(|()| None)
+                                .map_err(|e| e.to_string())
+                        }
+                        Some(username) => Err(format!("{} is no longer signed in", username)),
+                    },
+                    Err(e) => Err(e.to_string()),
+                };
+                match result {
+                    Ok(user) => {
+                        // A page that moves on by itself, rather than a
+                        // redirect, so the next request is same-site and the
+                        // Strict session cookies are sent with it.
+                        let mut response = Response::builder()
+                            .status(StatusCode::OK)
+                            .header(header::SET_COOKIE, clear_state_cookie);
+                        if let Some(user) = user {
+                            let tokens = state
+                                .auth_service
+                                .start_session(&user, &device_name(&headers));
+                            for cookie in session_cookies(&tokens, state.auth_service.lifetimes()) {
+                                response = response.header(header::SET_COOKIE, cookie);
+                            }
+                        }
+                        return Ok(response
+                            .header(header::CONTENT_TYPE, "text/html")
+                            .body(
+                                "<!DOCTYPE html><meta http-equiv=\"refresh\" content=\"0; url=/\"><a href=\"/\">Continue</a>"
+                                    .into(),
+                            )
+                            .unwrap());
+                    }
+                    Err(e) => {
+                        log::warn!("Single sign-on failed: {}", e);
+                        "Signing in failed, please try again".to_string()
+                    }
+                }
+            }
+            _ => "This sign-in link has expired, please try again".to_string(),
+        }
+    };
+
+    let data = json!({
+        "title": "Sign in",
+        "heading": "Sign in",
+        "main_content": format!("<p role=\"alert\">{}</p>", handlebars::html_escape(&error)),
+        "csrf_token": csrf_token
+    });
+    let rendered = state.handlebars.render("layout", &data)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::SET_COOKIE, clear_state_cookie)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(rendered.into())
+        .unwrap())
+}
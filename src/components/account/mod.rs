@@ -0,0 +1,220 @@
+use axum::{
+    extract::{ConnectInfo, Form, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    auth::{CurrentUser, CLEAR_SESSION_COOKIES},
+    components::login::describe_wait,
+    error::AppError,
+    htmx::HxResponse,
+    models::user::{Claims, Role},
+    services::auth_service::{now_secs, AuthError},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub new_password: String,
+    pub new_password_confirmation: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    pub password: String,
+}
+
+fn error_message(username: &str, e: AuthError) -> String {
+    match e {
+        AuthError::BadPassword => "Your password was not correct".to_string(),
+        AuthError::WeakPassword(reason) => reason.to_string(),
+        AuthError::TooManyAttempts(secs) => format!(
+            "Too many wrong passwords, please try again in {}",
+            describe_wait(secs)
+        ),
+        e => {
+            log::error!("Account update failed for {}: {}", username, e);
+            "Something went wrong, please try again".to_string()
+        }
+    }
+}
+
+fn render_account(
+    state: &AppState,
+    claims: &Claims,
+    mut data: serde_json::Value,
+) -> Result<Response, AppError> {
+    data["can_author"] = json!(claims.role >= Role::Author);
+    data["is_admin"] = json!(claims.role >= Role::Admin);
+    data["sessions"] = sessions_view(state, claims);
+    data["oidc_provider"] = json!(state.oidc.as_ref().map(|oidc| oidc.provider_name()));
+    let rendered = state.handlebars.render("account/account", &data)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(rendered.into())
+        .unwrap())
+}
+
+/// The user's active sessions, with the one making this request marked.
+fn sessions_view(state: &AppState, claims: &Claims) -> serde_json::Value {
+    let now = now_secs();
+    let sessions: Vec<_> = state
+        .auth_service
+        .list_sessions(&claims.sub)
+        .into_iter()
+        .map(|session| {
+            json!({
+                "id": session.id,
+                "device": session.device,
+                "last_seen": time_ago(now.saturating_sub(session.last_seen)),
+                "current": session.id == claims.sid
+            })
+        })
+        .collect();
+    json!(sessions)
+}
+
+fn time_ago(secs: u64) -> String {
+    match secs {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{} min ago", secs / 60),
+        3600..86400 => format!("{} h ago", secs / 3600),
+        _ => format!("{} days ago", secs / 86400),
+    }
+}
+
+/// Response for when the session making the request has just been ended.
+fn signed_out() -> Response {
+    let response: Response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::SET_COOKIE, CLEAR_SESSION_COOKIES[0])
+        .header(header::SET_COOKIE, CLEAR_SESSION_COOKIES[1])
+        .header(header::CONTENT_TYPE, "text/html")
+        .body("".into())
+        .unwrap();
+    (HxResponse::new().refresh(), response).into_response()
+}
+
+pub async fn account_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<Response, AppError> {
+    render_account(&state, &claims, json!({}))
+}
+
+pub async fn change_password_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<ChangePasswordForm>,
+) -> Result<Response, AppError> {
+    let username = &claims.sub;
+
+    let result = if form.new_password != form.new_password_confirmation {
+        Err(AuthError::WeakPassword("New passwords do not match"))
+    } else {
+        state.auth_service.change_password(
+            username,
+            &form.current_password,
+            &form.new_password,
+            addr.ip(),
+        )
+    };
+
+    match result {
+        Ok(()) => {
+            let response = render_account(
+                &state,
+                &claims,
+                json!({ "message": "Your password has been changed" }),
+            )?;
+            Ok((HxResponse::new().trigger("password-changed"), response).into_response())
+        }
+        Err(e) => render_account(
+            &state,
+            &claims,
+            json!({ "error": error_message(username, e) }),
+        ),
+    }
+}
+
+pub async fn delete_account_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<DeleteAccountForm>,
+) -> Result<Response, AppError> {
+    let username = &claims.sub;
+
+    if let Err(e) = state
+        .auth_service
+        .delete_account(username, &form.password, addr.ip())
+    {
+        return render_account(
+            &state,
+            &claims,
+            json!({ "error": error_message(username, e) }),
+        );
+    }
+    state.auth_service.end_all_sessions(username);
+    state.progress_service.forget_reader(username);
+
+    Ok((HxResponse::new().trigger("account-deleted"), signed_out()).into_response())
+}
+
+pub async fn end_session_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+    Path(session_id): Path<String>,
+) -> Result<Response, AppError> {
+    if !state.auth_service.end_session(&claims.sub, &session_id) {
+        return render_account(
+            &state,
+            &claims,
+            json!({ "error": "That session has already ended" }),
+        );
+    }
+    if session_id == claims.sid {
+        return Ok(signed_out());
+    }
+    render_account(
+        &state,
+        &claims,
+        json!({ "message": "The session has been logged out" }),
+    )
+}
+
+pub async fn end_all_sessions_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(claims): CurrentUser,
+) -> Result<Response, AppError> {
+    state.auth_service.end_all_sessions(&claims.sub);
+    Ok(signed_out())
+}
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/components/account", get(account_handler))
+        .route(
+            "/components/account/password",
+            post(change_password_handler),
+        )
+        .route("/components/account/delete", post(delete_account_handler))
+        .route(
+            "/components/account/sessions/{session_id}/logout",
+            post(end_session_handler),
+        )
+        .route(
+            "/components/account/sessions/logout",
+            post(end_all_sessions_handler),
+        )
+}
@@ -1,16 +1,23 @@
 use axum::{
     debug_handler,
-    extract::{Form, State},
-    http::{header, StatusCode},
-    response::Response,
+    extract::{ConnectInfo, Form, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::post,
     Router,
 };
 use serde::Deserialize;
 use serde_json::json;
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
-use crate::{models::user::UserCredentials, AppState};
+use crate::{
+    auth::{device_name, refresh_token, session_cookies, CLEAR_SESSION_COOKIES},
+    error::AppError,
+    htmx::HxResponse,
+    models::user::UserCredentials,
+    services::auth_service::AuthError,
+    AppState,
+};
 
 #[derive(Deserialize)]
 pub struct LoginForm {
@@ -18,83 +25,119 @@ pub struct LoginForm {
     pub password: String,
 }
 
-pub fn register_templates(handlebars: &mut handlebars::Handlebars) {
-    handlebars
-        .register_template_string("login", include_str!("./login.hbs"))
-        .expect("Failed to register login partial");
+/// Adds a "Sign in with ..." button to the login form when single sign-on
+/// is configured, and leaves it out otherwise.
+pub fn register_oidc_button(handlebars: &mut handlebars::Handlebars, provider: Option<&str>) {
+    let button = match provider {
+        Some(provider) => handlebars
+            .render("login/oidc_button", &json!({ "provider": provider }))
+            .expect("Failed to render single sign-on button"),
+        None => String::new(),
+    };
     handlebars
-        .register_template_string("logged_in", include_str!("./logged_in.hbs"))
-        .expect("Failed to register logged in template");
+        .register_partial("oidc_button", button)
+        .expect("Failed to register single sign-on button");
 }
 
 #[debug_handler]
 pub async fn login_handler(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Form(form): Form<LoginForm>,
-) -> Response {
+) -> Result<Response, AppError> {
     let credentials = UserCredentials {
         username: form.username.clone(),
         password: form.password.clone(),
     };
 
-    if state.auth_service.validate_credentials(&credentials) {
-        let token = state.auth_service.create_jwt(&form.username);
+    let result = state.auth_service.login(&credentials, addr.ip());
+    if let Ok(user) = result {
+        let tokens = state
+            .auth_service
+            .start_session(&user, &device_name(&headers));
 
         let data = json!({
-            "username": form.username,
+            "username": user.username,
             "error": null
         });
 
-        let rendered = state
-            .handlebars
-            .render("logged_in", &data)
-            .expect("Failed to render logged in template");
+        let rendered = state.handlebars.render("login/logged_in", &data)?;
 
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(
-                header::SET_COOKIE,
-                format!("auth={}; Path=/; HttpOnly; SameSite=Strict", token),
-            )
+        let mut response = Response::builder().status(StatusCode::OK);
+        for cookie in session_cookies(&tokens, state.auth_service.lifetimes()) {
+            response = response.header(header::SET_COOKIE, cookie);
+        }
+        let response: Response = response
             .header(header::CONTENT_TYPE, "text/html")
-            .header("HX-Trigger", "login-success")
-            .header("HX-Refresh", "true")
             .body(rendered.into())
-            .unwrap()
+            .unwrap();
+        Ok((
+            HxResponse::new().trigger("login-success").refresh(),
+            response,
+        )
+            .into_response())
     } else {
-        let rendered = state
-            .handlebars
-            .render(
-                "login",
-                &json!({
-                    "error": "Invalid username or password"
-                }),
-            )
-            .expect("Failed to render login template");
-        Response::builder()
+        let error = match result {
+            Err(AuthError::AccountLocked) => "This account is locked".to_string(),
+            Err(AuthError::TooManyAttempts(secs)) => format!(
+                "Too many failed attempts, please try again in {}",
+                describe_wait(secs)
+            ),
+            Err(AuthError::Store(e)) => {
+                log::error!("Login failed for {}: {}", form.username, e);
+                "Something went wrong, please try again".to_string()
+            }
+            _ => "Invalid username or password".to_string(),
+        };
+        let rendered = state.handlebars.render(
+            "login/login",
+            &json!({
+                "error": error
+            }),
+        )?;
+        Ok(Response::builder()
             .status(StatusCode::OK)
             .header(header::CONTENT_TYPE, "text/html")
             .body(rendered.into())
-            .unwrap()
+            .unwrap())
+    }
+}
+
+/// How long a throttled user has to wait, as the forms tell them.
+pub fn describe_wait(secs: u64) -> String {
+    match secs {
+        1 => "1 second".to_string(),
+        secs if secs < 60 => format!("{} seconds", secs),
+        secs => match secs.div_ceil(60) {
+            1 => "1 minute".to_string(),
+            minutes => format!("{} minutes", minutes),
+        },
     }
 }
 
-pub async fn logout_handler(State(state): State<Arc<AppState>>) -> Response {
-    let data = json!({
-        "title": "Storybuilder",
-        "heading": "Storybuilder",
-    });
+pub async fn logout_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(refresh_token) = refresh_token(&headers) {
+        state
+            .auth_service
+            .end_session_for_refresh_token(refresh_token);
+    }
+
     let rendered = state
         .handlebars
-        .render("index", &data)
-        .expect("Failed to render template");
+        .render("login/login", &json!({ "error": null }))?;
 
-    Response::builder()
+    let response: Response = Response::builder()
         .status(StatusCode::OK)
-        .header(header::SET_COOKIE, "auth=; Path=/; HttpOnly; Max-Age=0")
+        .header(header::SET_COOKIE, CLEAR_SESSION_COOKIES[0])
+        .header(header::SET_COOKIE, CLEAR_SESSION_COOKIES[1])
         .header(header::CONTENT_TYPE, "text/html")
         .body(rendered.into())
-        .unwrap()
+        .unwrap();
+    Ok((HxResponse::new().refresh(), response).into_response())
 }
 
 pub fn create_routes() -> Router<Arc<AppState>> {
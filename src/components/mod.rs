@@ -4,12 +4,13 @@ use axum::Router;
 
 use crate::AppState;
 
+pub mod account;
 pub mod login;
-
-pub fn register_templates(handlebars: &mut handlebars::Handlebars) {
-    login::register_templates(handlebars);
-}
+pub mod register;
 
 pub fn create_routes() -> Router<Arc<AppState>> {
-    Router::new().merge(login::create_routes())
+    Router::new()
+        .merge(account::create_routes())
+        .merge(login::create_routes())
+        .merge(register::create_routes())
 }
@@ -0,0 +1,109 @@
+use axum::{
+    extract::{Form, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    auth::{device_name, session_cookies},
+    error::AppError,
+    htmx::HxResponse,
+    services::auth_service::AuthError,
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct RegisterForm {
+    pub username: String,
+    pub password: String,
+    pub password_confirmation: String,
+}
+
+pub async fn register_form_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let rendered = state
+        .handlebars
+        .render("register/register", &json!({ "error": null }))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(rendered.into())
+        .unwrap())
+}
+
+pub async fn register_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Form(form): Form<RegisterForm>,
+) -> Result<Response, AppError> {
+    let result = if form.password != form.password_confirmation {
+        Err(AuthError::WeakPassword("Passwords do not match"))
+    } else {
+        state.auth_service.register(&form.username, &form.password)
+    };
+
+    match result {
+        Ok(user) => {
+            let tokens = state
+                .auth_service
+                .start_session(&user, &device_name(&headers));
+
+            let rendered = state
+                .handlebars
+                .render("login/logged_in", &json!({ "username": user.username }))?;
+
+            let mut response = Response::builder().status(StatusCode::OK);
+            for cookie in session_cookies(&tokens, state.auth_service.lifetimes()) {
+                response = response.header(header::SET_COOKIE, cookie);
+            }
+            let response: Response = response
+                .header(header::CONTENT_TYPE, "text/html")
+                .body(rendered.into())
+                .unwrap();
+            Ok((
+                HxResponse::new().trigger("register-success").refresh(),
+                response,
+            )
+                .into_response())
+        }
+        Err(e) => {
+            let error = match e {
+                AuthError::UsernameTaken => "That username is already taken".to_string(),
+                AuthError::InvalidUsername(reason) | AuthError::WeakPassword(reason) => {
+                    reason.to_string()
+                }
+                e => {
+                    log::error!("Registration failed for {}: {}", form.username, e);
+                    "Something went wrong, please try again".to_string()
+                }
+            };
+            let rendered = state.handlebars.render(
+                "register/register",
+                &json!({
+                    "username": form.username,
+                    "error": error
+                }),
+            )?;
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/html")
+                .body(rendered.into())
+                .unwrap())
+        }
+    }
+}
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/components/register",
+        get(register_form_handler).post(register_handler),
+    )
+}
@@ -0,0 +1,67 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::fmt;
+
+/// Something a handler could not do. Turning it into a response logs it
+/// and marks the response with an `ErrorPage`, which
+/// `pages::error::render_error_pages` renders as a page or fragment.
+#[derive(Debug)]
+pub enum AppError {
+    /// The URL names something that does not exist, e.g. "That book".
+    NotFound(&'static str),
+    Internal(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound(what) => write!(f, "{} does not exist", what),
+            AppError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<handlebars::RenderError> for AppError {
+    fn from(e: handlebars::RenderError) -> Self {
+        AppError::Internal(format!("failed to render template: {}", e))
+    }
+}
+
+/// What went wrong, in words fit to show the visitor.
+#[derive(Debug, Clone)]
+pub struct ErrorPage {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let page = match &self {
+            AppError::NotFound(_) => {
+                log::info!("{}", self);
+                ErrorPage {
+                    status: StatusCode::NOT_FOUND,
+                    message: self.to_string(),
+                }
+            }
+            AppError::Internal(message) => {
+                log::error!("{}", message);
+                ErrorPage {
+                    status: StatusCode::INTERNAL_SERVER_ERROR,
+                    message: "Something went wrong, please try again".to_string(),
+                }
+            }
+        };
+
+        let mut response = (
+            page.status,
+            [(header::CONTENT_TYPE, "text/plain")],
+            page.message.clone(),
+        )
+            .into_response();
+        response.extensions_mut().insert(page);
+        response
+    }
+}
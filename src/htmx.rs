@@ -0,0 +1,466 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{convert::Infallible, sync::Arc};
+
+use crate::{
+    auth::{csrf::CsrfToken, MaybeUser},
+    error::AppError,
+    AppState,
+};
+
+/// Renders a page's content template on its own for HTMX requests that swap
+/// it into the current page, and inside the layout for everything else:
+/// direct loads, boosted links and history restores, which all replace the
+/// whole document.
+pub struct HtmxPage {
+    state: Arc<AppState>,
+    fragment: bool,
+    username: Option<String>,
+    csrf_token: String,
+}
+
+impl HtmxPage {
+    /// Whether the request only wants the content, not the layout.
+    pub fn is_fragment(headers: &HeaderMap) -> bool {
+        headers.contains_key("HX-Request")
+            && !headers.contains_key("HX-Boosted")
+            && !headers.contains_key("HX-History-Restore-Request")
+    }
+
+    pub fn render<T: Serialize>(
+        &self,
+        title: &str,
+        template: &str,
+        data: &T,
+    ) -> Result<Response, AppError> {
+        let content = self.state.handlebars.render(template, data)?;
+        let body = if self.fragment {
+            content
+        } else {
+            self.state.handlebars.render(
+                "layout",
+                &json!({
+                    "title": title,
+                    "heading": title,
+                    "username": self.username,
+                    "main_content": content,
+                    "csrf_token": self.csrf_token
+                }),
+            )?
+        };
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html")
+            .header(
+                header::VARY,
+                HeaderValue::from_static("HX-Request, HX-Boosted, HX-History-Restore-Request"),
+            )
+            .body(body.into())
+            .unwrap())
+    }
+}
+
+impl FromRequestParts<Arc<AppState>> for HtmxPage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let MaybeUser(claims) = MaybeUser::from_request_parts(parts, state).await?;
+        let CsrfToken(csrf_token) = CsrfToken::from_request_parts(parts, state).await?;
+        Ok(HtmxPage {
+            state: state.clone(),
+            fragment: Self::is_fragment(&parts.headers),
+            username: claims.map(|claims| claims.sub),
+            csrf_token,
+        })
+    }
+}
+
+/// How htmx swaps a response into the page, for `HX-Reswap` and
+/// `HX-Location`.
+// Only some swap styles are used by a handler yet.
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Swap {
+    InnerHtml,
+    OuterHtml,
+    BeforeBegin,
+    AfterBegin,
+    BeforeEnd,
+    AfterEnd,
+    Delete,
+    None,
+}
+
+impl Swap {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Swap::InnerHtml => "innerHTML",
+            Swap::OuterHtml => "outerHTML",
+            Swap::BeforeBegin => "beforebegin",
+            Swap::AfterBegin => "afterbegin",
+            Swap::BeforeEnd => "beforeend",
+            Swap::AfterEnd => "afterend",
+            Swap::Delete => "delete",
+            Swap::None => "none",
+        }
+    }
+}
+
+/// Where `HX-Location` sends the browser: a path loaded with an AJAX request
+/// instead of a full navigation, optionally into a given target.
+#[derive(Debug, Clone, Serialize)]
+pub struct HxLocation {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    swap: Option<&'static str>,
+}
+
+impl HxLocation {
+    pub fn new(path: impl Into<String>) -> Self {
+        HxLocation {
+            path: path.into(),
+            target: None,
+            swap: None,
+        }
+    }
+
+    pub fn target(mut self, selector: impl Into<String>) -> Self {
+        self.target = Some(selector.into());
+        self
+    }
+
+    pub fn swap(mut self, swap: Swap) -> Self {
+        self.swap = Some(swap.as_str());
+        self
+    }
+
+    fn header_value(&self) -> String {
+        if self.target.is_none() && self.swap.is_none() {
+            self.path.clone()
+        } else {
+            serde_json::to_string(self).unwrap()
+        }
+    }
+}
+
+/// Events for `HX-Trigger` and `HX-Trigger-After-Swap`. Sent as a list of
+/// names unless one of them carries a payload, then as a JSON object.
+#[derive(Debug, Default)]
+struct Triggers(Vec<(String, Option<serde_json::Value>)>);
+
+impl Triggers {
+    fn header_value(&self) -> Option<String> {
+        if self.0.is_empty() {
+            return None;
+        }
+        if self.0.iter().all(|(_, payload)| payload.is_none()) {
+            let names: Vec<_> = self.0.iter().map(|(name, _)| name.as_str()).collect();
+            return Some(names.join(", "));
+        }
+        let events: serde_json::Map<_, _> = self
+            .0
+            .iter()
+            .map(|(name, payload)| {
+                (
+                    name.clone(),
+                    payload.clone().unwrap_or(serde_json::Value::Null),
+                )
+            })
+            .collect();
+        Some(serde_json::Value::Object(events).to_string())
+    }
+}
+
+/// The htmx response headers for a handler's response, added by returning
+/// it alongside the response:
+///
+/// ```ignore
+/// Ok((HxResponse::new().trigger("login-success").refresh(), response).into_response())
+/// ```
+#[derive(Debug, Default)]
+pub struct HxResponse {
+    trigger: Triggers,
+    trigger_after_swap: Triggers,
+    push_url: Option<String>,
+    replace_url: Option<String>,
+    retarget: Option<String>,
+    reswap: Option<Swap>,
+    redirect: Option<String>,
+    location: Option<HxLocation>,
+    refresh: bool,
+}
+
+impl HxResponse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Triggers `event` on the requesting element as soon as the response
+    /// arrives.
+    pub fn trigger(mut self, event: impl Into<String>) -> Self {
+        self.trigger.0.push((event.into(), None));
+        self
+    }
+
+    /// Sends the browser to `url` with a full page load.
+    pub fn redirect(mut self, url: impl Into<String>) -> Self {
+        self.redirect = Some(url.into());
+        self
+    }
+
+    /// Sends the browser to a location without a full page load.
+    pub fn location(mut self, location: impl Into<HxLocation>) -> Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    /// Reloads the whole page.
+    pub fn refresh(mut self) -> Self {
+        self.refresh = true;
+        self
+    }
+}
+
+/// Headers no handler sends yet.
+#[cfg_attr(not(test), allow(dead_code))]
+impl HxResponse {
+    /// Triggers `event` with `payload` as its `detail`, sending the header
+    /// as `{"event": payload}`.
+    pub fn trigger_with(mut self, event: impl Into<String>, payload: impl Serialize) -> Self {
+        let payload = serde_json::to_value(payload).unwrap_or(serde_json::Value::Null);
+        self.trigger.0.push((event.into(), Some(payload)));
+        self
+    }
+
+    /// Triggers `event` once the response has been swapped in.
+    pub fn trigger_after_swap(mut self, event: impl Into<String>) -> Self {
+        self.trigger_after_swap.0.push((event.into(), None));
+        self
+    }
+
+    pub fn trigger_after_swap_with(
+        mut self,
+        event: impl Into<String>,
+        payload: impl Serialize,
+    ) -> Self {
+        let payload = serde_json::to_value(payload).unwrap_or(serde_json::Value::Null);
+        self.trigger_after_swap
+            .0
+            .push((event.into(), Some(payload)));
+        self
+    }
+
+    /// Pushes `url` onto the browser history.
+    pub fn push_url(mut self, url: impl Into<String>) -> Self {
+        self.push_url = Some(url.into());
+        self
+    }
+
+    /// Replaces the current history entry with `url`.
+    pub fn replace_url(mut self, url: impl Into<String>) -> Self {
+        self.replace_url = Some(url.into());
+        self
+    }
+
+    /// Swaps the response into the element matching `selector` instead of
+    /// the request's target.
+    pub fn retarget(mut self, selector: impl Into<String>) -> Self {
+        self.retarget = Some(selector.into());
+        self
+    }
+
+    /// Swaps the response in with `swap` instead of the request's `hx-swap`.
+    pub fn reswap(mut self, swap: Swap) -> Self {
+        self.reswap = Some(swap);
+        self
+    }
+}
+
+impl From<&str> for HxLocation {
+    fn from(path: &str) -> Self {
+        HxLocation::new(path)
+    }
+}
+
+impl From<String> for HxLocation {
+    fn from(path: String) -> Self {
+        HxLocation::new(path)
+    }
+}
+
+impl IntoResponseParts for HxResponse {
+    type Error = AppError;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let headers = [
+            ("HX-Trigger", self.trigger.header_value()),
+            (
+                "HX-Trigger-After-Swap",
+                self.trigger_after_swap.header_value(),
+            ),
+            ("HX-Push-Url", self.push_url),
+            ("HX-Replace-Url", self.replace_url),
+            ("HX-Retarget", self.retarget),
+            (
+                "HX-Reswap",
+                self.reswap.map(|swap| swap.as_str().to_string()),
+            ),
+            ("HX-Redirect", self.redirect),
+            (
+                "HX-Location",
+                self.location.map(|location| location.header_value()),
+            ),
+            ("HX-Refresh", self.refresh.then(|| "true".to_string())),
+        ];
+        for (name, value) in headers {
+            let Some(value) = value else { continue };
+            let value = HeaderValue::try_from(value)
+                .map_err(|_| AppError::Internal(format!("invalid {} header", name)))?;
+            res.headers_mut().insert(name, value);
+        }
+        Ok(res)
+    }
+}
+
+/// A headers-only response, for when htmx only needs to be told what to do.
+impl IntoResponse for HxResponse {
+    fn into_response(self) -> Response {
+        (self, ()).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(response: HxResponse) -> HeaderMap {
+        response.into_response().headers().clone()
+    }
+
+    #[test]
+    fn an_empty_response_sets_no_headers() {
+        assert!(headers(HxResponse::new()).is_empty());
+    }
+
+    #[test]
+    fn triggers_are_sent_as_a_list_of_names() {
+        let headers = headers(
+            HxResponse::new()
+                .trigger("login-success")
+                .trigger("refresh-library"),
+        );
+        assert_eq!(headers["HX-Trigger"], "login-success, refresh-library");
+    }
+
+    #[test]
+    fn a_trigger_with_a_payload_is_sent_as_json() {
+        let headers = headers(
+            HxResponse::new()
+                .trigger("login-success")
+                .trigger_with("show-message", json!({ "level": "info", "text": "Saved" })),
+        );
+        let value: serde_json::Value =
+            serde_json::from_str(headers["HX-Trigger"].to_str().unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "login-success": null,
+                "show-message": { "level": "info", "text": "Saved" }
+            })
+        );
+    }
+
+    #[test]
+    fn triggers_after_swap() {
+        let plain = headers(HxResponse::new().trigger_after_swap("page-shown"));
+        assert_eq!(plain["HX-Trigger-After-Swap"], "page-shown");
+        assert!(!plain.contains_key("HX-Trigger"));
+
+        let headers = headers(HxResponse::new().trigger_after_swap_with("page-shown", 3));
+        assert_eq!(headers["HX-Trigger-After-Swap"], r#"{"page-shown":3}"#);
+    }
+
+    #[test]
+    fn push_url() {
+        let headers = headers(HxResponse::new().push_url("/pages/book/1"));
+        assert_eq!(headers["HX-Push-Url"], "/pages/book/1");
+    }
+
+    #[test]
+    fn replace_url() {
+        let headers = headers(HxResponse::new().replace_url("/pages/book/1/page/2"));
+        assert_eq!(headers["HX-Replace-Url"], "/pages/book/1/page/2");
+    }
+
+    #[test]
+    fn retarget() {
+        let headers = headers(HxResponse::new().retarget("#book-page"));
+        assert_eq!(headers["HX-Retarget"], "#book-page");
+    }
+
+    #[test]
+    fn reswap_sends_every_swap_style() {
+        let styles = [
+            (Swap::InnerHtml, "innerHTML"),
+            (Swap::OuterHtml, "outerHTML"),
+            (Swap::BeforeBegin, "beforebegin"),
+            (Swap::AfterBegin, "afterbegin"),
+            (Swap::BeforeEnd, "beforeend"),
+            (Swap::AfterEnd, "afterend"),
+            (Swap::Delete, "delete"),
+            (Swap::None, "none"),
+        ];
+        for (swap, value) in styles {
+            assert_eq!(headers(HxResponse::new().reswap(swap))["HX-Reswap"], value);
+        }
+    }
+
+    #[test]
+    fn redirect_and_refresh() {
+        let headers = headers(HxResponse::new().redirect("/").refresh());
+        assert_eq!(headers["HX-Redirect"], "/");
+        assert_eq!(headers["HX-Refresh"], "true");
+    }
+
+    #[test]
+    fn a_plain_location_is_just_the_path() {
+        let headers = headers(HxResponse::new().location("/pages/book/1"));
+        assert_eq!(headers["HX-Location"], "/pages/book/1");
+    }
+
+    #[test]
+    fn a_location_with_a_target_is_sent_as_json() {
+        let location = HxLocation::new("/pages/book/1/page/2")
+            .target("#book-page")
+            .swap(Swap::OuterHtml);
+        let headers = headers(HxResponse::new().location(location));
+        let value: serde_json::Value =
+            serde_json::from_str(headers["HX-Location"].to_str().unwrap()).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "path": "/pages/book/1/page/2",
+                "target": "#book-page",
+                "swap": "outerHTML"
+            })
+        );
+    }
+
+    #[test]
+    fn an_invalid_header_value_is_an_error() {
+        let response = (HxResponse::new().trigger("bad\nevent"), "body").into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!response.headers().contains_key("HX-Trigger"));
+    }
+}
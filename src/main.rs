@@ -1,14 +1,33 @@
-use axum::Router;
+use axum::{middleware, Router};
 use dotenvy::dotenv;
 use handlebars::Handlebars;
+use models::user::Role;
+use services::auth_service::{now_secs, TokenLifetimes};
+use services::book_service::BookService;
+use services::login_throttle::LoginThrottle;
+use services::oidc_client::{OidcClient, OidcConfig};
+use services::session_store::SessionStore;
+use services::signing_keys::SigningKeys;
+use services::user_repository::{
+    InMemoryUserRepository, SqliteUserRepository, UserRepository, UserStoreError,
+};
 use std::env;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Instant};
 use tower_http::services::ServeDir;
 
+mod auth;
 mod components;
+mod error;
+mod htmx;
 mod models;
 mod pages;
 mod services;
+mod templates;
 
 pub fn get_jwt_secret() -> Vec<u8> {
     dotenv().ok();
@@ -17,38 +36,307 @@ pub fn get_jwt_secret() -> Vec<u8> {
         .into_bytes()
 }
 
+/// The PEM files listed in `JWT_KEYS`, if it is set.
+pub fn get_jwt_key_files() -> Option<Vec<PathBuf>> {
+    dotenv().ok();
+    let paths = env::var("JWT_KEYS").ok()?;
+    Some(
+        paths
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+    )
+}
+
+/// The keys tokens are signed with: the PEM files listed in `JWT_KEYS`,
+/// signing with the first, or else the `JWT_SECRET` HMAC secret. When both
+/// are set, as while switching from a secret to key files, tokens signed
+/// with the secret are still accepted until they expire.
+pub fn get_signing_keys(lifetimes: TokenLifetimes) -> SigningKeys {
+    let Some(paths) = get_jwt_key_files() else {
+        return SigningKeys::hmac(&get_jwt_secret());
+    };
+    let keys = SigningKeys::from_pem_files(&paths)
+        .unwrap_or_else(|e| panic!("Failed to load JWT_KEYS: {}", e));
+    match env::var("JWT_SECRET") {
+        Ok(secret) => {
+            let mut retiring = SigningKeys::hmac(secret.as_bytes());
+            retiring.replace(keys, now_secs(), lifetimes.access_secs);
+            retiring
+        }
+        Err(_) => keys,
+    }
+}
+
+/// How often to reload `JWT_KEYS`, if `JWT_KEYS_RELOAD_SECS` is set. They
+/// are also reloaded on SIGHUP.
+pub fn get_key_reload_interval() -> Option<Duration> {
+    dotenv().ok();
+    env::var("JWT_KEYS_RELOAD_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Where visitors who are not signed in are sent when they open a protected page.
+pub fn get_login_redirect() -> String {
+    dotenv().ok();
+    env::var("LOGIN_REDIRECT").unwrap_or_else(|_| "/".to_string())
+}
+
+pub fn get_progress_file() -> PathBuf {
+    dotenv().ok();
+    env::var("PROGRESS_FILE")
+        .unwrap_or_else(|_| "progress.json".to_string())
+        .into()
+}
+
+/// Where users and their sessions are stored: a SQLite file path, or
+/// `memory` to keep them in memory.
+pub fn get_user_db() -> String {
+    dotenv().ok();
+    env::var("USER_DB").unwrap_or_else(|_| "users.db".to_string())
+}
+
+pub fn get_token_lifetimes() -> TokenLifetimes {
+    dotenv().ok();
+    let secs = |name: &str, default: u64| {
+        env::var(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    TokenLifetimes {
+        access_secs: secs("ACCESS_TOKEN_TTL_SECS", 15 * 60),
+        refresh_secs: secs("REFRESH_TOKEN_TTL_SECS", 14 * 24 * 60 * 60),
+    }
+}
+
+/// Single sign-on settings, if `OIDC_ISSUER` and `OIDC_CLIENT_ID` are set.
+pub fn get_oidc_config() -> Option<OidcConfig> {
+    dotenv().ok();
+    Some(OidcConfig {
+        issuer: env::var("OIDC_ISSUER").ok()?,
+        client_id: env::var("OIDC_CLIENT_ID").ok()?,
+        client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+        redirect_url: env::var("OIDC_REDIRECT_URL")
+            .unwrap_or_else(|_| "http://localhost:3000/auth/oidc/callback".to_string()),
+        provider_name: env::var("OIDC_PROVIDER_NAME")
+            .unwrap_or_else(|_| "single sign-on".to_string()),
+        scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+    })
+}
+
+pub fn get_books_dir() -> PathBuf {
+    dotenv().ok();
+    env::var("BOOKS_DIR")
+        .unwrap_or_else(|_| "books".to_string())
+        .into()
+}
+
+const EXPORT_USAGE: &str = "usage: mustachestory export-site <book-id> <out-dir>
+       mustachestory export-epub <book-id> <out-file> [--shuffle]";
+
+/// `export-site <book-id> <out-dir>` writes a book as a static HTML site and
+/// `export-epub <book-id> <out-file> [--shuffle]` as an EPUB, instead of
+/// starting the server.
+fn export(handlebars: &Handlebars, book_service: &BookService, command: &str, args: &[String]) {
+    let (book_id, out, shuffle) = match args {
+        [book_id, out] => (book_id, Path::new(out), false),
+        [book_id, out, flag] if command == "export-epub" && flag == "--shuffle" => {
+            (book_id, Path::new(out), true)
+        }
+        _ => {
+            eprintln!("{}", EXPORT_USAGE);
+            std::process::exit(2);
+        }
+    };
+    let Some(book) = book_id
+        .parse()
+        .ok()
+        .and_then(|book_id| book_service.get_book(book_id))
+    else {
+        eprintln!("No book with id {}", book_id);
+        std::process::exit(1);
+    };
+    let exported = if command == "export-epub" {
+        services::epub_exporter::export_epub(book, out, shuffle)
+    } else {
+        services::site_exporter::export_site(handlebars, book, out)
+    };
+    match exported {
+        Ok(pages) => println!(
+            "Exported {} pages of {} to {}",
+            pages,
+            book.title,
+            out.display()
+        ),
+        Err(e) => {
+            eprintln!("Failed to export {}: {}", book.title, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Reloads the `JWT_KEYS` files whenever the server gets SIGHUP and every
+/// `interval`, so keys can be rotated without a restart. If the files
+/// cannot be loaded, the current keys stay in use.
+fn spawn_key_reloader(
+    auth_service: Arc<services::auth_service::AuthService>,
+    paths: Vec<PathBuf>,
+    interval: Option<Duration>,
+) {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        let mut interval =
+            interval.map(|period| time::interval_at(Instant::now() + period, period));
+        loop {
+            let tick = async {
+                match &mut interval {
+                    Some(interval) => {
+                        interval.tick().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = tick => {}
+            }
+            match SigningKeys::from_pem_files(&paths) {
+                Ok(keys) => {
+                    auth_service.reload_keys(keys);
+                    log::info!("Reloaded JWT_KEYS");
+                }
+                Err(e) => log::error!("Failed to reload JWT_KEYS, keeping the current keys: {}", e),
+            }
+        }
+    });
+}
 
 pub struct AppState {
     handlebars: Handlebars<'static>,
     auth_service: Arc<services::auth_service::AuthService>,
-    book_service: Arc<services::book_service::BookService>,
+    book_service: Arc<BookService>,
+    progress_service: Arc<services::progress_service::ProgressService>,
+    oidc: Option<Arc<OidcClient>>,
+    login_redirect: String,
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
     let mut handlebars = Handlebars::new();
-    components::register_templates(&mut handlebars);
-    pages::register_templates(&mut handlebars);
-    pages::index::register_templates(&mut handlebars);
-    pages::book::register_templates(&mut handlebars);
+    // Debug builds register template files from disk, see `templates`.
+    handlebars.set_dev_mode(cfg!(debug_assertions));
+    templates::register_templates(&mut handlebars);
+
+    let (book_service, load_errors) = BookService::from_dir(&get_books_dir());
+    for error in &load_errors {
+        log::error!("Skipping story file {}", error);
+    }
+    for report in book_service.get_validation_reports() {
+        if report.has_errors() {
+            log::error!("Refusing {}", report);
+        } else if !report.is_clean() {
+            log::warn!("Loaded {}", report);
+        }
+    }
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command @ ("export-site" | "export-epub")) = args.first().map(String::as_str) {
+        export(&handlebars, &book_service, command, &args[1..]);
+        return;
+    }
+    let book_service = Arc::new(book_service);
+    let (users, sessions): (Arc<dyn UserRepository>, _) = match get_user_db().as_str() {
+        "memory" => (Arc::new(InMemoryUserRepository::new()), SessionStore::new()),
+        path => (
+            Arc::new(
+                SqliteUserRepository::open(path.as_ref()).expect("Failed to open user database"),
+            ),
+            SessionStore::open(path.as_ref(), now_secs()).expect("Failed to open session store"),
+        ),
+    };
+    let lifetimes = get_token_lifetimes();
+    let auth_service = services::auth_service::AuthService::new(
+        get_signing_keys(lifetimes),
+        users,
+        sessions,
+        lifetimes,
+        LoginThrottle::in_memory(),
+    );
+    if let (Ok(username), Ok(password)) =
+        (env::var("INITIAL_USERNAME"), env::var("INITIAL_PASSWORD"))
+    {
+        match auth_service.create_user(&username, &password, Role::Admin) {
+            Ok(_) => log::info!("Created initial user {}", username),
+            Err(UserStoreError::UsernameTaken) => {}
+            Err(e) => log::error!("Failed to create initial user {}: {}", username, e),
+        }
+    }
+
+    let oidc = match get_oidc_config() {
+        Some(config) => match OidcClient::discover(config).await {
+            Ok(client) => Some(Arc::new(client)),
+            Err(e) => {
+                log::error!("Single sign-on is disabled: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    components::login::register_oidc_button(
+        &mut handlebars,
+        oidc.as_ref().map(|client| client.provider_name()),
+    );
+
+    let auth_service = Arc::new(auth_service);
+    if let Some(paths) = get_jwt_key_files() {
+        spawn_key_reloader(auth_service.clone(), paths, get_key_reload_interval());
+    }
 
-    let book_service = Arc::new(services::book_service::BookService::new());
     let state = Arc::new(AppState {
         handlebars,
-        auth_service: Arc::new(services::auth_service::AuthService::new(get_jwt_secret())),
+        auth_service,
         book_service,
+        progress_service: Arc::new(services::progress_service::ProgressService::from_file(
+            get_progress_file(),
+        )),
+        oidc,
+        login_redirect: get_login_redirect(),
     });
 
     let app = Router::new()
         .nest_service("/static", ServeDir::new("static"))
         .merge(components::create_routes())
+        .merge(auth::oidc::create_routes())
+        .merge(auth::jwks::create_routes())
         .merge(pages::index::create_routes())
         .merge(pages::book::create_routes())
+        .merge(pages::reports::create_routes())
+        .merge(pages::admin::create_routes())
+        .fallback(pages::error::not_found_handler)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            pages::error::render_error_pages,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::refresh_session,
+        ))
+        .layer(middleware::from_fn(auth::csrf::protect))
         .with_state(state);
 
     println!("Server starting on http://localhost:3000");
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
@@ -1,3 +1,4 @@
+use crate::models::progress::PlaythroughState;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -7,17 +8,108 @@ pub struct Book {
     pub summary: String,
     pub pages: Vec<Page>,
     pub starting_page: u32,
+    /// The flags, counters and inventory every playthrough starts with.
+    #[serde(default, skip_serializing_if = "PlaythroughState::is_empty")]
+    pub initial_state: PlaythroughState,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Page {
     pub id: u32,
     pub content: String,
+    #[serde(default)]
     pub choices: Vec<Choice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ending: Option<Ending>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndingKind {
+    Victory,
+    Death,
+    Neutral,
+    Cliffhanger,
+}
+
+impl EndingKind {
+    /// The kind of ending a story tag or class marks, as in a Twine passage
+    /// tagged `victory`, an Ink `# death` tag or a Markdown `{.ending}`
+    /// heading. `ending` marks a neutral ending.
+    pub fn from_tag(tag: &str) -> Option<EndingKind> {
+        match tag {
+            "ending" | "neutral" => Some(EndingKind::Neutral),
+            "victory" => Some(EndingKind::Victory),
+            "death" => Some(EndingKind::Death),
+            "cliffhanger" => Some(EndingKind::Cliffhanger),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ending {
+    pub kind: EndingKind,
+    pub title: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
     pub text: String,
     pub target_page_id: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub effects: Vec<Effect>,
+    /// How the choice is shown when its conditions are not met.
+    #[serde(default)]
+    pub when_unmet: UnmetChoice,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmetChoice {
+    #[default]
+    Hidden,
+    Disabled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    FlagSet { flag: String },
+    FlagUnset { flag: String },
+    HasItem { item: String },
+    LacksItem { item: String },
+    CounterAtLeast { counter: String, value: i64 },
+    CounterBelow { counter: String, value: i64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Effect {
+    SetFlag { flag: String },
+    ClearFlag { flag: String },
+    Increment { counter: String, by: i64 },
+    AddItem { item: String },
+    RemoveItem { item: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ending_kinds_from_tags() {
+        assert_eq!(EndingKind::from_tag("ending"), Some(EndingKind::Neutral));
+        assert_eq!(EndingKind::from_tag("neutral"), Some(EndingKind::Neutral));
+        assert_eq!(EndingKind::from_tag("victory"), Some(EndingKind::Victory));
+        assert_eq!(EndingKind::from_tag("death"), Some(EndingKind::Death));
+        assert_eq!(
+            EndingKind::from_tag("cliffhanger"),
+            Some(EndingKind::Cliffhanger)
+        );
+        assert_eq!(EndingKind::from_tag("Victory"), None);
+        assert_eq!(EndingKind::from_tag("title: The End"), None);
+    }
 }
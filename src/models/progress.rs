@@ -0,0 +1,75 @@
+use crate::models::book::{Choice, Condition, Effect};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReaderProgress {
+    pub current_page: Option<u32>,
+    #[serde(default)]
+    pub found_endings: HashSet<u32>,
+    #[serde(default)]
+    pub state: PlaythroughState,
+}
+
+/// Flags, counters and inventory for a single playthrough of a book.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlaythroughState {
+    #[serde(default)]
+    pub flags: BTreeSet<String>,
+    #[serde(default)]
+    pub counters: BTreeMap<String, i64>,
+    #[serde(default)]
+    pub inventory: BTreeSet<String>,
+}
+
+impl PlaythroughState {
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty() && self.counters.is_empty() && self.inventory.is_empty()
+    }
+
+    pub fn is_met(&self, condition: &Condition) -> bool {
+        match condition {
+            Condition::FlagSet { flag } => self.flags.contains(flag),
+            Condition::FlagUnset { flag } => !self.flags.contains(flag),
+            Condition::HasItem { item } => self.inventory.contains(item),
+            Condition::LacksItem { item } => !self.inventory.contains(item),
+            Condition::CounterAtLeast { counter, value } => self.counter(counter) >= *value,
+            Condition::CounterBelow { counter, value } => self.counter(counter) < *value,
+        }
+    }
+
+    pub fn is_available(&self, choice: &Choice) -> bool {
+        choice.conditions.iter().all(|c| self.is_met(c))
+    }
+
+    pub fn apply(&mut self, effect: &Effect) {
+        match effect {
+            Effect::SetFlag { flag } => {
+                self.flags.insert(flag.clone());
+            }
+            Effect::ClearFlag { flag } => {
+                self.flags.remove(flag);
+            }
+            Effect::Increment { counter, by } => {
+                *self.counters.entry(counter.clone()).or_default() += by;
+            }
+            Effect::AddItem { item } => {
+                self.inventory.insert(item.clone());
+            }
+            Effect::RemoveItem { item } => {
+                self.inventory.remove(item);
+            }
+        }
+    }
+
+    pub fn counter(&self, name: &str) -> i64 {
+        self.counters.get(name).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgressRecord {
+    pub username: String,
+    pub book_id: u32,
+    pub progress: ReaderProgress,
+}
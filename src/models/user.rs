@@ -1,10 +1,53 @@
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// What a user may do. Roles are ordered, so each role includes the powers
+/// of the ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Reader,
+    Author,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Reader, Role::Author, Role::Editor, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Author => "author",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("unknown role {}", s))
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
     pub iat: usize,
+    #[serde(default)]
+    pub role: Role,
+    /// Unique id of this token, checked against the revocation list.
+    pub jti: String,
+    /// Id of the session the token was issued in.
+    pub sid: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -12,3 +55,11 @@ pub struct UserCredentials {
     pub username: String,
     pub password: String,
 }
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub username: String,
+    pub password_hash: String,
+    pub locked: bool,
+    pub role: Role,
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{Form, Path, State},
+    http::{header, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    auth::{require, Authorized},
+    error::AppError,
+    htmx::HtmxPage,
+    models::user::Role,
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    pub role: Role,
+}
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/pages/admin/users", get(admin_users_handler))
+        .route("/pages/admin/users/{username}/role", post(set_role_handler))
+}
+
+fn users_view(state: &AppState, error: Option<&str>) -> serde_json::Value {
+    let users: Vec<_> = state
+        .auth_service
+        .list_users()
+        .unwrap_or_else(|e| {
+            log::error!("Failed to list users: {}", e);
+            Vec::new()
+        })
+        .into_iter()
+        .map(|user| {
+            json!({
+                "username": user.username,
+                "roles": Role::ALL
+                    .iter()
+                    .map(|role| json!({
+                        "value": role.as_str(),
+                        "selected": *role == user.role
+                    }))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    json!({ "users": users, "error": error })
+}
+
+pub async fn admin_users_handler(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::Admin>,
+    htmx: HtmxPage,
+) -> Result<Response, AppError> {
+    htmx.render("Users", "admin/admin_users", &users_view(&state, None))
+}
+
+pub async fn set_role_handler(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::Admin>,
+    Path(username): Path<String>,
+    Form(form): Form<RoleForm>,
+) -> Result<Response, AppError> {
+    let error = if username == auth.claims.sub && form.role < Role::Admin {
+        Some("You cannot remove your own admin role")
+    } else if let Err(e) = state.auth_service.set_role(&username, form.role) {
+        log::error!("Failed to set role for {}: {}", username, e);
+        Some("Could not change that user's role")
+    } else {
+        None
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(
+            state
+                .handlebars
+                .render("admin/admin_users", &users_view(&state, error))?
+                .into(),
+        )
+        .unwrap())
+}
@@ -1,22 +1,24 @@
-use crate::{get_jwt_secret, models::user::Claims, AppState};
+use crate::{
+    auth::{require, Authorized},
+    error::AppError,
+    htmx::{HtmxPage, HxLocation, HxResponse, Swap},
+    models::{
+        book::{Book, Page, UnmetChoice},
+        progress::PlaythroughState,
+    },
+    AppState,
+};
 use axum::{
     debug_handler,
     extract::State,
     http::{header, StatusCode},
-    response::Response,
-    routing::get,
+    response::{IntoResponse, Response},
+    routing::{get, post},
     Router,
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
 use serde_json::json;
 use std::sync::Arc;
 
-pub fn register_templates(handlebars: &mut handlebars::Handlebars) {
-    handlebars
-        .register_template_string("book_page", include_str!("./book_page.hbs"))
-        .expect("Failed to register book page template");
-}
-
 pub fn create_routes() -> Router<Arc<AppState>> {
     Router::new()
         .route("/pages/book/{book_id}", get(book_start_handler))
@@ -24,225 +26,328 @@ pub fn create_routes() -> Router<Arc<AppState>> {
             "/pages/book/{book_id}/page/{page_id}",
             get(book_page_handler),
         )
+        .route(
+            "/pages/book/{book_id}/page/{page_id}/choice/{choice_index}",
+            post(book_choice_handler),
+        )
+        .route("/pages/book/{book_id}/endings", get(book_endings_handler))
+        .route("/pages/book/{book_id}/reset", post(book_reset_handler))
+        .route(
+            "/pages/book/{book_id}/preview/{page_id}",
+            get(book_preview_handler),
+        )
 }
 
+/// Starts a new playthrough, unless the reader is part way through one, in
+/// which case they are sent back to the page they are on.
 #[debug_handler]
 pub async fn book_start_handler(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
+    auth: Authorized<require::Reader>,
+    htmx: HtmxPage,
     axum::extract::Path(book_id): axum::extract::Path<u32>,
-) -> Response {
-    // Check for valid auth cookie
-    let mut authenticated = false;
-    if let Some(cookie) = headers.get(header::COOKIE) {
-        if let Some(cookie_str) = cookie.to_str().ok() {
-            if let Some(token) = cookie_str
-                .split(';')
-                .find(|s| s.trim().starts_with("auth="))
-                .and_then(|s| s.trim().strip_prefix("auth="))
-            {
-                if state.auth_service.validate_jwt(token).is_some() {
-                    authenticated = true;
-                }
-            }
-        }
-    }
-
-    if !authenticated {
-        return Response::builder()
-            .status(StatusCode::SEE_OTHER)
-            .header(header::LOCATION, "/")
-            .body("Redirecting...".into())
-            .unwrap();
-    }
+) -> Result<Response, AppError> {
+    let username = auth.claims.sub;
 
-    let is_htmx = headers.get("HX-Request").is_some();
     let book = state
         .book_service
         .get_book(book_id)
-        .expect("Book not found");
+        .ok_or(AppError::NotFound("That book"))?;
+
+    let progress = state.progress_service.get_progress(&username, book_id);
+    if let Some(page) = progress
+        .current_page
+        .and_then(|page_id| state.book_service.get_page(book_id, page_id))
+        .filter(|page| page.ending.is_none())
+    {
+        return Ok(see_other(page_url(book_id, page.id)));
+    }
 
     let current_page = state
         .book_service
         .get_starting_page(book_id)
-        .expect("Starting page not found");
+        .ok_or_else(|| AppError::Internal(format!("book {} has no starting page", book_id)))?;
+
+    state.progress_service.start(&username, book, current_page);
 
     let data = json!({
         "title": book.title,
-        "page": current_page,
+        "page": page_view(book, current_page, &book.initial_state),
         "book_id": book.id
     });
 
-    if is_htmx {
-        let rendered = state
-            .handlebars
-            .render("book_page", &data)
-            .expect("Failed to render book page template");
-
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "text/html")
-            .body(rendered.into())
-            .unwrap()
-    } else {
-        // Return full page for direct browser requests
-        let mut full_data = json!({
-            "title": book.title,
-            "heading": book.title,
-            "username": null,
-            "state": {
-                "library": state.book_service.get_library()
-            }
-        });
-
-        // Add username if available
-        if let Some(cookie) = headers.get(header::COOKIE) {
-            if let Some(cookie_str) = cookie.to_str().ok() {
-                if let Some(token) = cookie_str
-                    .split(';')
-                    .find(|s| s.trim().starts_with("auth="))
-                    .and_then(|s| s.trim().strip_prefix("auth="))
-                {
-                    if let Ok(token_data) = decode::<Claims>(
-                        token,
-                        &DecodingKey::from_secret(&get_jwt_secret()),
-                        &Validation::default(),
-                    ) {
-                        full_data["username"] = json!(token_data.claims.sub);
-                    }
-                }
-            }
-        }
-
-        // Add the book page content to the main section
-        let book_page_content = state
-            .handlebars
-            .render("book_page", &data)
-            .expect("Failed to render book page template");
-        full_data["main_content"] = json!(book_page_content);
-
-        let rendered = state
-            .handlebars
-            .render("layout", &full_data)
-            .expect("Failed to render template");
-
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "text/html")
-            .body(rendered.into())
-            .unwrap()
-    }
+    htmx.render(&book.title, "book/book_page", &data)
 }
 
+/// Shows the page the reader is on. Any other page id sends them to it, so
+/// pages can only be reached by taking choices.
 pub async fn book_page_handler(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
+    auth: Authorized<require::Reader>,
+    htmx: HtmxPage,
     axum::extract::Path((book_id, page_id)): axum::extract::Path<(u32, u32)>,
-) -> Response {
-    // Check for valid auth cookie
-    let mut authenticated = false;
-    if let Some(cookie) = headers.get(header::COOKIE) {
-        if let Some(cookie_str) = cookie.to_str().ok() {
-            if let Some(token) = cookie_str
-                .split(';')
-                .find(|s| s.trim().starts_with("auth="))
-                .and_then(|s| s.trim().strip_prefix("auth="))
-            {
-                if decode::<Claims>(
-                    token,
-                    &DecodingKey::from_secret(&get_jwt_secret()),
-                    &Validation::default(),
-                )
-                .is_ok()
-                {
-                    authenticated = true;
-                }
-            }
-        }
-    }
+) -> Result<Response, AppError> {
+    let username = auth.claims.sub;
 
-    if !authenticated {
-        return Response::builder()
-            .status(StatusCode::SEE_OTHER)
-            .header(header::LOCATION, "/")
-            .body("Redirecting...".into())
-            .unwrap();
-    }
-
-    let is_htmx = headers.get("HX-Request").is_some();
     let book = state
         .book_service
         .get_book(book_id)
-        .expect("Book not found");
+        .ok_or(AppError::NotFound("That book"))?;
+
+    let progress = state.progress_service.get_progress(&username, book_id);
+    match progress.current_page {
+        Some(current) if current == page_id => {}
+        Some(current) => return Ok(see_other(page_url(book_id, current))),
+        None => return Ok(see_other(format!("/pages/book/{}", book_id))),
+    }
 
     let current_page = state
         .book_service
         .get_page(book_id, page_id)
-        .expect("Page not found");
+        .ok_or(AppError::NotFound("That page"))?;
 
     let data = json!({
         "title": book.title,
-        "page": current_page,
+        "page": page_view(book, current_page, &progress.state),
         "book_id": book.id
     });
 
-    if is_htmx {
-        let rendered = state
-            .handlebars
-            .render("book_page", &data)
-            .expect("Failed to render book page template");
-
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "text/html")
-            .body(rendered.into())
-            .unwrap()
+    htmx.render(&book.title, "book/book_page", &data)
+}
+
+/// Applies the chosen choice's effects to the reader's playthrough and sends
+/// them on to the target page. Choices that are unavailable, or taken from a
+/// page the reader is no longer on, send them back to their current page.
+pub async fn book_choice_handler(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::Reader>,
+    headers: axum::http::HeaderMap,
+    axum::extract::Path((book_id, page_id, choice_index)): axum::extract::Path<(u32, u32, usize)>,
+) -> Result<Response, AppError> {
+    let username = auth.claims.sub;
+
+    let book = state
+        .book_service
+        .get_book(book_id)
+        .ok_or(AppError::NotFound("That book"))?;
+
+    let target_page_id = state
+        .progress_service
+        .take_choice(&username, book, page_id, choice_index)
+        .or_else(|| {
+            state
+                .progress_service
+                .get_progress(&username, book_id)
+                .current_page
+        })
+        .unwrap_or(page_id);
+    let location = page_url(book_id, target_page_id);
+
+    if headers.get("HX-Request").is_some() {
+        let location = HxLocation::new(location)
+            .target("#book-page")
+            .swap(Swap::OuterHtml);
+        Ok(HxResponse::new().location(location).into_response())
     } else {
-        // Return full page for direct browser requests
-        let mut full_data = json!({
-            "title": book.title,
-            "heading": book.title,
-            "username": null,
-            "state": {
-                "library": state.book_service.get_library()
-            }
-        });
-
-        // Add username if available
-        if let Some(cookie) = headers.get(header::COOKIE) {
-            if let Some(cookie_str) = cookie.to_str().ok() {
-                if let Some(token) = cookie_str
-                    .split(';')
-                    .find(|s| s.trim().starts_with("auth="))
-                    .and_then(|s| s.trim().strip_prefix("auth="))
-                {
-                    if let Ok(token_data) = decode::<Claims>(
-                        token,
-                        &DecodingKey::from_secret(&get_jwt_secret()),
-                        &Validation::default(),
-                    ) {
-                        full_data["username"] = json!(token_data.claims.sub);
-                    }
-                }
-            }
+        Ok(see_other(location))
+    }
+}
+
+/// Lets editors proofread any page of a book without playing through it.
+/// The page is rendered as in the static export: every choice is a plain
+/// link to the preview of its target, and nothing is recorded.
+pub async fn book_preview_handler(
+    State(state): State<Arc<AppState>>,
+    _auth: Authorized<require::Editor>,
+    htmx: HtmxPage,
+    axum::extract::Path((book_id, page_id)): axum::extract::Path<(u32, u32)>,
+) -> Result<Response, AppError> {
+    let book = state
+        .book_service
+        .get_book(book_id)
+        .ok_or(AppError::NotFound("That book"))?;
+    let page = state
+        .book_service
+        .get_page(book_id, page_id)
+        .ok_or(AppError::NotFound("That page"))?;
+
+    let choices: Vec<_> = page
+        .choices
+        .iter()
+        .filter(|choice| has_page(book, choice.target_page_id))
+        .map(|choice| {
+            json!({
+                "text": choice.text,
+                "href": preview_url(book_id, choice.target_page_id),
+                "available": true
+            })
+        })
+        .collect();
+    let data = json!({
+        "title": book.title,
+        "book_id": book.id,
+        "export": true,
+        "start_href": preview_url(book_id, book.starting_page),
+        "page": {
+            "id": page.id,
+            "content": page.content,
+            "ending": page.ending,
+            "choices": choices
         }
+    });
+
+    htmx.render(&book.title, "book/book_page", &data)
+}
+
+fn has_page(book: &Book, page_id: u32) -> bool {
+    book.pages.iter().any(|page| page.id == page_id)
+}
+
+fn preview_url(book_id: u32, page_id: u32) -> String {
+    format!("/pages/book/{}/preview/{}", book_id, page_id)
+}
+
+fn page_url(book_id: u32, page_id: u32) -> String {
+    format!("/pages/book/{}/page/{}", book_id, page_id)
+}
+
+fn see_other(location: String) -> Response {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body("Redirecting...".into())
+        .unwrap()
+}
+
+pub async fn book_endings_handler(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::Reader>,
+    htmx: HtmxPage,
+    axum::extract::Path(book_id): axum::extract::Path<u32>,
+) -> Result<Response, AppError> {
+    let username = auth.claims.sub;
+
+    let book = state
+        .book_service
+        .get_book(book_id)
+        .ok_or(AppError::NotFound("That book"))?;
+
+    let found = state.progress_service.get_found_endings(&username, book_id);
+    let endings: Vec<_> = book
+        .pages
+        .iter()
+        .filter_map(|page| {
+            page.ending.as_ref().map(|ending| {
+                json!({
+                    "title": ending.title,
+                    "kind": ending.kind,
+                    "found": found.contains(&page.id)
+                })
+            })
+        })
+        .collect();
+    let found_count = endings.iter().filter(|e| e["found"] == true).count();
+
+    let data = json!({
+        "title": book.title,
+        "book_id": book.id,
+        "endings": endings,
+        "found_count": found_count,
+        "total_count": endings.len()
+    });
+
+    htmx.render(&book.title, "book/book_endings", &data)
+}
+
+pub async fn book_reset_handler(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::Reader>,
+    axum::extract::Path(book_id): axum::extract::Path<u32>,
+) -> Result<Response, AppError> {
+    let username = auth.claims.sub;
+
+    let book = state
+        .book_service
+        .get_book(book_id)
+        .ok_or(AppError::NotFound("That book"))?;
+
+    state.progress_service.reset(&username, book_id);
+
+    let data = json!({
+        "id": book.id,
+        "title": book.title,
+        "summary": book.summary,
+        "current_page": null
+    });
+
+    let rendered = state.handlebars.render("index/book_card", &data)?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(rendered.into())
+        .unwrap())
+}
+
+/// Template data for a page, with each choice marked as available or not
+/// for the reader's playthrough and hidden choices left out.
+/// The page as the reader sees it. Choices leading to a page the book does
+/// not have are left out, as the validator's report already lists them.
+fn page_view(book: &Book, page: &Page, playthrough: &PlaythroughState) -> serde_json::Value {
+    let choices: Vec<_> = page
+        .choices
+        .iter()
+        .enumerate()
+        .filter(|(_, choice)| has_page(book, choice.target_page_id))
+        .filter_map(|(index, choice)| {
+            let available = playthrough.is_available(choice);
+            if !available && choice.when_unmet == UnmetChoice::Hidden {
+                return None;
+            }
+            Some(json!({
+                "index": index,
+                "text": choice.text,
+                "available": available
+            }))
+        })
+        .collect();
+
+    json!({
+        "id": page.id,
+        "content": page.content,
+        "ending": page.ending,
+        "choices": choices,
+        "inventory": playthrough.inventory
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choices_to_missing_pages_are_left_out() {
+        let book: Book = serde_json::from_value(json!({
+            "id": 1,
+            "title": "The Cellar",
+            "summary": "",
+            "starting_page": 1,
+            "pages": [
+                { "id": 1, "content": "A cellar door.", "choices": [
+                    { "text": "Fall through the floor", "target_page_id": 9 },
+                    { "text": "Go down", "target_page_id": 2 }
+                ] },
+                { "id": 2, "content": "Darkness.", "ending": { "kind": "neutral", "title": "Lost" } }
+            ]
+        }))
+        .unwrap();
 
-        // Add the book page content to the main section
-        let book_page_content = state
-            .handlebars
-            .render("book_page", &data)
-            .expect("Failed to render book page template");
-        full_data["main_content"] = json!(book_page_content);
-
-        let rendered = state
-            .handlebars
-            .render("layout", &full_data)
-            .expect("Failed to render template");
-
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "text/html")
-            .body(rendered.into())
-            .unwrap()
+        let view = page_view(&book, &book.pages[0], &book.initial_state);
+        // The remaining choice keeps its index, which is what the reader
+        // posts back to take it.
+        assert_eq!(
+            view["choices"],
+            json!([{ "index": 1, "text": "Go down", "available": true }])
+        );
     }
 }
@@ -0,0 +1,83 @@
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    auth::{auth_token, csrf::CsrfToken},
+    error::{AppError, ErrorPage},
+    htmx::HtmxPage,
+    AppState,
+};
+
+/// Answers URLs that match no route.
+pub async fn not_found_handler() -> AppError {
+    AppError::NotFound("That page")
+}
+
+/// Renders responses marked with an `ErrorPage` as the error template, inside
+/// the layout for browser requests and on its own for HTMX requests.
+pub async fn render_error_pages(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers().clone();
+    let csrf_token = request
+        .extensions()
+        .get::<CsrfToken>()
+        .map(|CsrfToken(token)| token.clone())
+        .unwrap_or_default();
+
+    let response = next.run(request).await;
+    let Some(page) = response.extensions().get::<ErrorPage>().cloned() else {
+        return response;
+    };
+
+    let heading = page.status.canonical_reason().unwrap_or("Error");
+    let data = json!({
+        "heading": heading,
+        "message": page.message
+    });
+    let rendered = state
+        .handlebars
+        .render("error/error", &data)
+        .and_then(|content| {
+            if HtmxPage::is_fragment(&headers) {
+                return Ok(content);
+            }
+            let username = auth_token(&headers)
+                .and_then(|token| state.auth_service.validate_jwt(token))
+                .map(|claims| claims.sub);
+            state.handlebars.render(
+                "layout",
+                &json!({
+                    "title": heading,
+                    "heading": heading,
+                    "username": username,
+                    "main_content": content,
+                    "csrf_token": csrf_token
+                }),
+            )
+        });
+
+    match rendered {
+        Ok(rendered) => {
+            let (mut parts, _) = response.into_parts();
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("text/html"),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, rendered.into())
+        }
+        Err(e) => {
+            log::error!("Failed to render error page: {}", e);
+            (page.status, page.message).into_response()
+        }
+    }
+}
@@ -1,21 +1,12 @@
-use axum::{extract::State, http::header, response::Html, routing::get, Router};
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use axum::{extract::State, response::Html, routing::get, Router};
 use serde_json::json;
 use std::sync::Arc;
 
-use crate::{get_jwt_secret, models::user::Claims, AppState};
-
-pub fn register_templates(handlebars: &mut handlebars::Handlebars) {
-    handlebars
-        .register_template_string(
-            "non_logged_in_content",
-            include_str!("./non_logged_in_content.hbs"),
-        )
-        .expect("Failed to register non logged in content template");
-    handlebars
-        .register_template_string("logged_in_content", include_str!("./logged_in_content.hbs"))
-        .expect("Failed to register logged in content template");
-}
+use crate::{
+    auth::{csrf::CsrfToken, MaybeUser},
+    error::AppError,
+    AppState,
+};
 
 pub fn create_routes() -> Router<Arc<AppState>> {
     Router::new().route("/", get(index_handler))
@@ -23,53 +14,57 @@ pub fn create_routes() -> Router<Arc<AppState>> {
 
 pub async fn index_handler(
     State(state): State<Arc<AppState>>,
-    headers: axum::http::HeaderMap,
-) -> Html<String> {
+    MaybeUser(claims): MaybeUser,
+    CsrfToken(csrf_token): CsrfToken,
+) -> Result<Html<String>, AppError> {
     let mut data = json!({
         "title": "Storybuilder",
         "heading": "Storybuilder",
         "state": {
             "library": state.book_service.get_library()
         },
-        "main_content": ""
+        "main_content": "",
+        "csrf_token": csrf_token
     });
 
-    let content_template = if let Some(cookie) = headers.get(header::COOKIE) {
-        if let Some(cookie_str) = cookie.to_str().ok() {
-            if let Some(token) = cookie_str
-                .split(';')
-                .find(|s| s.trim().starts_with("auth="))
-                .and_then(|s| s.trim().strip_prefix("auth="))
-            {
-                if let Ok(token_data) = decode::<Claims>(
-                    token,
-                    &DecodingKey::from_secret(&get_jwt_secret()),
-                    &Validation::default(),
-                ) {
-                    data["username"] = json!(token_data.claims.sub);
-                    "logged_in_content"
-                } else {
-                    "non_logged_in_content"
-                }
-            } else {
-                "non_logged_in_content"
-            }
-        } else {
-            "non_logged_in_content"
-        }
+    let content_template = if let Some(claims) = claims {
+        data["state"]["library"] = json!(library_with_progress(&state, &claims.sub));
+        data["username"] = json!(claims.sub);
+        "index/logged_in_content"
     } else {
-        "non_logged_in_content"
+        "index/non_logged_in_content"
     };
 
-    data["main_content"] = json!(state
-        .handlebars
-        .render(content_template, &data)
-        .expect("Failed to render content template"));
+    data["main_content"] = json!(state.handlebars.render(content_template, &data)?);
 
-    let rendered = state
-        .handlebars
-        .render("layout", &data)
-        .expect("Failed to render template");
+    let rendered = state.handlebars.render("layout", &data)?;
+
+    Ok(Html(rendered))
+}
 
-    Html(rendered)
+fn library_with_progress(state: &AppState, username: &str) -> Vec<serde_json::Value> {
+    state
+        .book_service
+        .get_library()
+        .iter()
+        .map(|book| {
+            // A playthrough that reached an ending is over.
+            let current_page = state
+                .progress_service
+                .get_progress(username, book.id)
+                .current_page
+                .filter(|&page_id| {
+                    state
+                        .book_service
+                        .get_page(book.id, page_id)
+                        .is_some_and(|page| page.ending.is_none())
+                });
+            json!({
+                "id": book.id,
+                "title": book.title,
+                "summary": book.summary,
+                "current_page": current_page
+            })
+        })
+        .collect()
 }
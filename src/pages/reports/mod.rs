@@ -0,0 +1,55 @@
+use axum::{extract::State, response::Response, routing::get, Router};
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::{
+    auth::{require, Authorized, RoleRequirement},
+    error::AppError,
+    htmx::HtmxPage,
+    AppState,
+};
+
+pub fn create_routes() -> Router<Arc<AppState>> {
+    Router::new().route("/pages/reports", get(reports_handler))
+}
+
+/// Lists the validation report of every story file, including books that
+/// were refused at load time. Editors also get a link to proofread each
+/// loaded book.
+pub async fn reports_handler(
+    State(state): State<Arc<AppState>>,
+    auth: Authorized<require::Author>,
+    htmx: HtmxPage,
+) -> Result<Response, AppError> {
+    let can_edit = require::Editor::allows(auth.claims.role);
+    let reports: Vec<_> = state
+        .book_service
+        .get_validation_reports()
+        .iter()
+        .map(|report| {
+            let preview = state
+                .book_service
+                .get_book(report.book_id)
+                .filter(|_| can_edit)
+                .map(|book| format!("/pages/book/{}/preview/{}", book.id, book.starting_page));
+            json!({
+                "title": report.title,
+                "preview": preview,
+                "issues": report
+                    .issues
+                    .iter()
+                    .map(|issue| json!({
+                        "severity": issue.severity(),
+                        "message": issue.to_string()
+                    }))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+
+    htmx.render(
+        "Story reports",
+        "reports/reports",
+        &json!({ "reports": reports }),
+    )
+}
@@ -1,47 +1,623 @@
-use crate::models::user::{Claims, UserCredentials};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use crate::models::user::{Claims, Role, User, UserCredentials};
+use crate::services::login_throttle::LoginThrottle;
+use crate::services::oidc_client::Identity;
+use crate::services::session_store::{random_token, SessionInfo, SessionStore};
+use crate::services::signing_keys::SigningKeys;
+use crate::services::user_repository::{UserRepository, UserStoreError};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+#[derive(Debug)]
+pub enum AuthError {
+    UnknownUser,
+    BadPassword,
+    AccountLocked,
+    /// Too many failed logins; the seconds to wait before trying again.
+    TooManyAttempts(u64),
+    UsernameTaken,
+    /// The single sign-on identity is already linked to another user.
+    IdentityInUse,
+    InvalidUsername(&'static str),
+    WeakPassword(&'static str),
+    Store(UserStoreError),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnknownUser => write!(f, "unknown user"),
+            AuthError::BadPassword => write!(f, "bad password"),
+            AuthError::AccountLocked => write!(f, "account is locked"),
+            AuthError::TooManyAttempts(secs) => {
+                write!(f, "too many failed logins, retry in {}s", secs)
+            }
+            AuthError::UsernameTaken => write!(f, "username is already taken"),
+            AuthError::IdentityInUse => write!(f, "identity is linked to another user"),
+            AuthError::InvalidUsername(reason) => write!(f, "{}", reason),
+            AuthError::WeakPassword(reason) => write!(f, "{}", reason),
+            AuthError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<UserStoreError> for AuthError {
+    fn from(e: UserStoreError) -> Self {
+        match e {
+            UserStoreError::UsernameTaken => AuthError::UsernameTaken,
+            e => AuthError::Store(e),
+        }
+    }
+}
+
+/// How long access and refresh tokens stay valid, in seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+    pub access_secs: u64,
+    pub refresh_secs: u64,
+}
+
+pub struct SessionTokens {
+    pub access_token: String,
+    /// Absent when a refresh token was accepted without being replaced.
+    pub refresh_token: Option<String>,
+}
+
 pub struct AuthService {
-    secret: Vec<u8>,
+    keys: RwLock<SigningKeys>,
+    users: Arc<dyn UserRepository>,
+    sessions: SessionStore,
+    lifetimes: TokenLifetimes,
+    throttle: LoginThrottle,
 }
 
 impl AuthService {
-    pub fn new(secret: Vec<u8>) -> Self {
-        Self { secret }
+    pub fn new(
+        keys: SigningKeys,
+        users: Arc<dyn UserRepository>,
+        sessions: SessionStore,
+        lifetimes: TokenLifetimes,
+        throttle: LoginThrottle,
+    ) -> Self {
+        Self {
+            keys: RwLock::new(keys),
+            users,
+            sessions,
+            lifetimes,
+            throttle,
+        }
+    }
+
+    pub fn lifetimes(&self) -> TokenLifetimes {
+        self.lifetimes
     }
 
-    pub fn validate_credentials(&self, credentials: &UserCredentials) -> bool {
-        credentials.username == "richard" && credentials.password == "secret"
+    /// The public keys our tokens can be verified with, as a JWKS document.
+    pub fn jwks(&self) -> serde_json::Value {
+        self.keys.read().unwrap().jwks()
     }
 
-    pub fn create_jwt(&self, username: &str) -> String {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
+    /// Signs new tokens with `keys` from now on. Keys that were dropped keep
+    /// verifying until the access tokens they signed have expired.
+    pub fn reload_keys(&self, keys: SigningKeys) {
+        self.keys
+            .write()
             .unwrap()
-            .as_secs() as usize;
+            .replace(keys, now_secs(), self.lifetimes.access_secs);
+    }
+
+    /// Starts a session for a user who has just signed in on `device`,
+    /// issuing an access token and the session's first refresh token.
+    pub fn start_session(&self, user: &User, device: &str) -> SessionTokens {
+        let (session_id, refresh_token) = self.sessions.create(
+            &user.username,
+            device,
+            now_secs(),
+            self.lifetimes.refresh_secs,
+        );
+        SessionTokens {
+            access_token: self.create_jwt(&user.username, user.role, &session_id),
+            refresh_token: Some(refresh_token),
+        }
+    }
+
+    /// Exchanges a refresh token for a new access token, rotating the refresh
+    /// token. The user is looked up again so role changes, locks and deleted
+    /// accounts take effect.
+    pub fn refresh_session(&self, refresh_token: &str) -> Option<SessionTokens> {
+        let now = now_secs();
+        let (session, refresh_token) =
+            self.sessions
+                .rotate(refresh_token, now, self.lifetimes.refresh_secs)?;
+        let user = match self.users.find(&session.username) {
+            Ok(Some(user)) if !user.locked => user,
+            Ok(_) => {
+                self.sessions.end_all(&session.username, now);
+                return None;
+            }
+            Err(e) => {
+                log::error!("Failed to refresh session for {}: {}", session.username, e);
+                return None;
+            }
+        };
+        Some(SessionTokens {
+            access_token: self.create_jwt(&user.username, user.role, &session.id),
+            refresh_token,
+        })
+    }
+
+    /// The signed-in user's active sessions, most recently seen first.
+    pub fn list_sessions(&self, username: &str) -> Vec<SessionInfo> {
+        self.sessions.list(username, now_secs())
+    }
+
+    /// Ends one of `username`'s sessions. Returns false if there was no such
+    /// session for that user.
+    pub fn end_session(&self, username: &str, session_id: &str) -> bool {
+        match self.sessions.get(session_id) {
+            Some(session) if session.username == username => {
+                self.sessions.end(session_id, now_secs());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ends the session a refresh token belongs to, e.g. on logout.
+    pub fn end_session_for_refresh_token(&self, refresh_token: &str) {
+        if let Some(session) = self.sessions.find_by_refresh_token(refresh_token) {
+            self.sessions.end(&session.id, now_secs());
+        }
+    }
+
+    pub fn end_all_sessions(&self, username: &str) {
+        self.sessions.end_all(username, now_secs());
+    }
+
+    /// Checks a login attempt from `ip`, refusing it without looking at the
+    /// password while the username or address is throttled. The attempt is
+    /// counted as a failure before the password is checked and taken back
+    /// if it was not a wrong guess.
+    pub fn login(&self, credentials: &UserCredentials, ip: IpAddr) -> Result<User, AuthError> {
+        self.throttle
+            .check_and_reserve(&credentials.username, ip, now_secs())
+            .map_err(AuthError::TooManyAttempts)?;
+        match self.authenticate(credentials) {
+            Ok(user) => {
+                self.throttle.record_success(&user.username, ip);
+                Ok(user)
+            }
+            Err(e @ (AuthError::UnknownUser | AuthError::BadPassword)) => Err(e),
+            Err(e) => {
+                self.throttle.release(&credentials.username, ip);
+                Err(e)
+            }
+        }
+    }
+
+    /// Finds the local user for an identity vouched for by the OpenID Connect
+    /// provider, by its issuer and subject alone. On first sign-in a new
+    /// reader is created for it, named after its email or preferred username
+    /// with a random suffix when that name is taken. The names a provider
+    /// reports are never trusted to pick an existing account; that takes
+    /// `link_identity` from a signed-in session.
+    pub fn login_with_identity(&self, identity: &Identity) -> Result<User, AuthError> {
+        let user = match self.users.find_by_identity(&identity.iss, &identity.sub)? {
+            Some(user) => user,
+            None => {
+                let user = self.create_identity_user(identity)?;
+                self.users
+                    .link_identity(&identity.iss, &identity.sub, &user.username)?;
+                log::info!(
+                    "Created user {} for {} identity {}",
+                    user.username,
+                    identity.iss,
+                    identity.sub
+                );
+                user
+            }
+        };
+
+        if user.locked {
+            return Err(AuthError::AccountLocked);
+        }
+        Ok(user)
+    }
+
+    /// Links an identity to the signed-in user `username`, so they can sign
+    /// in with it from then on.
+    pub fn link_identity(&self, username: &str, identity: &Identity) -> Result<(), AuthError> {
+        match self.users.find_by_identity(&identity.iss, &identity.sub)? {
+            Some(user) if user.username == username => Ok(()),
+            Some(_) => Err(AuthError::IdentityInUse),
+            None => {
+                self.users
+                    .link_identity(&identity.iss, &identity.sub, username)?;
+                log::info!(
+                    "Linked {} identity {} to user {}",
+                    identity.iss,
+                    identity.sub,
+                    username
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Creates a reader for a new identity under a name no one has yet.
+    fn create_identity_user(&self, identity: &Identity) -> Result<User, AuthError> {
+        let name = username_for_identity(identity);
+        let mut username = name.clone();
+        for _ in 0..5 {
+            match self.create_user(&username, &random_token(), Role::Reader) {
+                Err(UserStoreError::UsernameTaken) => {
+                    let base: String = name.chars().take(25).collect();
+                    username = format!("{}-{}", base, &random_token()[..6]);
+                }
+                result => return Ok(result?),
+            }
+        }
+        Err(AuthError::UsernameTaken)
+    }
+
+    /// Checks a username and password. Unknown users are checked against a
+    /// dummy hash, so they take as long to refuse as wrong passwords and
+    /// the time taken does not tell which usernames exist.
+    pub fn authenticate(&self, credentials: &UserCredentials) -> Result<User, AuthError> {
+        let Some(user) = self.users.find(&credentials.username)? else {
+            verify_password(&credentials.password, dummy_hash());
+            return Err(AuthError::UnknownUser);
+        };
+
+        if !verify_password(&credentials.password, &user.password_hash) {
+            return Err(AuthError::BadPassword);
+        }
+
+        if user.locked {
+            return Err(AuthError::AccountLocked);
+        }
+
+        Ok(user)
+    }
+
+    /// Creates an account for a new reader after checking the username and
+    /// password strength.
+    pub fn register(&self, username: &str, password: &str) -> Result<User, AuthError> {
+        check_username(username)?;
+        check_password_strength(username, password)?;
+        Ok(self.create_user(username, password, Role::Reader)?)
+    }
+
+    /// Changes a signed-in user's password. The current password is checked
+    /// like a login from `ip`, so a stolen session cannot be used to guess it
+    /// any faster than the login form allows.
+    pub fn change_password(
+        &self,
+        username: &str,
+        current_password: &str,
+        new_password: &str,
+        ip: IpAddr,
+    ) -> Result<(), AuthError> {
+        let credentials = UserCredentials {
+            username: username.to_string(),
+            password: current_password.to_string(),
+        };
+        let mut user = self.login(&credentials, ip)?;
+        check_password_strength(username, new_password)?;
+        user.password_hash = hash_password(new_password);
+        Ok(self.users.update(user)?)
+    }
+
+    /// Deletes a signed-in user's account once their password, checked like
+    /// a login from `ip`, is confirmed.
+    pub fn delete_account(
+        &self,
+        username: &str,
+        password: &str,
+        ip: IpAddr,
+    ) -> Result<(), AuthError> {
+        let credentials = UserCredentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        self.login(&credentials, ip)?;
+        Ok(self.users.delete(username)?)
+    }
+
+    pub fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        role: Role,
+    ) -> Result<User, UserStoreError> {
+        let user = User {
+            username: username.to_string(),
+            password_hash: hash_password(password),
+            locked: false,
+            role,
+        };
+        self.users.insert(user.clone())?;
+        Ok(user)
+    }
+
+    pub fn list_users(&self) -> Result<Vec<User>, UserStoreError> {
+        self.users.list()
+    }
+
+    pub fn set_role(&self, username: &str, role: Role) -> Result<(), UserStoreError> {
+        let mut user = self.users.find(username)?.ok_or(UserStoreError::NotFound)?;
+        user.role = role;
+        self.users.update(user)
+    }
+
+    fn create_jwt(&self, username: &str, role: Role, session_id: &str) -> String {
+        let now = now_secs();
+        let exp = now + self.lifetimes.access_secs;
+        let jti = random_token();
+        self.sessions
+            .record_access_token(session_id, &jti, now, exp);
 
         let claims = Claims {
             sub: username.to_string(),
-            exp: now + 3600,
-            iat: now,
+            exp: exp as usize,
+            iat: now as usize,
+            role,
+            jti,
+            sid: session_id.to_string(),
         };
 
-        encode(
-            &Header::default(),
-            &claims,
-            &EncodingKey::from_secret(&self.secret),
-        )
-        .unwrap()
+        self.keys.read().unwrap().sign(&claims)
     }
 
     pub fn validate_jwt(&self, token: &str) -> Option<Claims> {
-        decode::<Claims>(
-            token,
-            &DecodingKey::from_secret(&self.secret),
-            &Validation::default(),
+        self.keys
+            .read()
+            .unwrap()
+            .verify::<Claims>(token)
+            .filter(|claims| {
+                self.sessions
+                    .check_access_token(&claims.sid, &claims.jti, now_secs())
+            })
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn check_username(username: &str) -> Result<(), AuthError> {
+    if username.len() < 3 || username.len() > 32 {
+        return Err(AuthError::InvalidUsername(
+            "Username must be between 3 and 32 characters",
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(AuthError::InvalidUsername(
+            "Username may only contain letters, numbers, '_' and '-'",
+        ));
+    }
+    Ok(())
+}
+
+/// A local username for a new identity: the verified email's local part or
+/// the preferred username, cleaned up to the characters usernames allow.
+fn username_for_identity(identity: &Identity) -> String {
+    let verified_email = identity
+        .email
+        .as_deref()
+        .filter(|_| identity.email_verified == Some(true))
+        .and_then(|email| email.split('@').next());
+    let claimed = verified_email
+        .or(identity.preferred_username.as_deref())
+        .unwrap_or_default();
+    let username: String = claimed
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(32)
+        .collect();
+    if check_username(&username).is_ok() {
+        username
+    } else {
+        format!("user-{}", &random_token()[..8])
+    }
+}
+
+fn check_password_strength(username: &str, password: &str) -> Result<(), AuthError> {
+    if password.chars().count() < 10 {
+        return Err(AuthError::WeakPassword(
+            "Password must be at least 10 characters",
+        ));
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err(AuthError::WeakPassword(
+            "Password must not be the same as your username",
+        ));
+    }
+    let has_letter = password.chars().any(|c| c.is_alphabetic());
+    let has_other = password.chars().any(|c| !c.is_alphabetic());
+    if !has_letter || !has_other {
+        return Err(AuthError::WeakPassword(
+            "Password must mix letters with numbers or symbols",
+        ));
+    }
+    Ok(())
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// A hash made like real ones, for checking passwords of unknown users.
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| hash_password(&random_token()))
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::login_throttle::ThrottlePolicy;
+    use crate::services::user_repository::InMemoryUserRepository;
+
+    fn auth_service(users: Arc<InMemoryUserRepository>) -> AuthService {
+        AuthService::new(
+            SigningKeys::hmac(b"test secret"),
+            users,
+            SessionStore::new(),
+            TokenLifetimes {
+                access_secs: 60,
+                refresh_secs: 600,
+            },
+            LoginThrottle::in_memory(),
         )
-        .map(|data| data.claims)
-        .ok()
+    }
+
+    fn credentials(username: &str, password: &str) -> UserCredentials {
+        UserCredentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn authenticate_accepts_the_right_password() {
+        let auth = auth_service(Arc::new(InMemoryUserRepository::new()));
+        auth.create_user("alice", "correct horse 1", Role::Author)
+            .unwrap();
+
+        let user = auth
+            .authenticate(&credentials("alice", "correct horse 1"))
+            .unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.role, Role::Author);
+        assert_ne!(user.password_hash, "correct horse 1");
+    }
+
+    #[test]
+    fn authenticate_refuses_a_wrong_password() {
+        let auth = auth_service(Arc::new(InMemoryUserRepository::new()));
+        auth.create_user("alice", "correct horse 1", Role::Reader)
+            .unwrap();
+
+        assert!(matches!(
+            auth.authenticate(&credentials("alice", "Correct horse 1")),
+            Err(AuthError::BadPassword)
+        ));
+    }
+
+    #[test]
+    fn authenticate_refuses_an_unknown_user() {
+        let auth = auth_service(Arc::new(InMemoryUserRepository::new()));
+
+        assert!(matches!(
+            auth.authenticate(&credentials("nobody", "correct horse 1")),
+            Err(AuthError::UnknownUser)
+        ));
+    }
+
+    #[test]
+    fn authenticate_refuses_a_locked_user() {
+        let users = Arc::new(InMemoryUserRepository::new());
+        let auth = auth_service(users.clone());
+        let mut user = auth
+            .create_user("alice", "correct horse 1", Role::Reader)
+            .unwrap();
+        user.locked = true;
+        users.update(user).unwrap();
+
+        assert!(matches!(
+            auth.authenticate(&credentials("alice", "correct horse 1")),
+            Err(AuthError::AccountLocked)
+        ));
+        // A wrong password does not reveal that the account is locked.
+        assert!(matches!(
+            auth.authenticate(&credentials("alice", "wrong horse 1")),
+            Err(AuthError::BadPassword)
+        ));
+    }
+
+    #[test]
+    fn password_checks_on_the_account_page_are_throttled() {
+        let auth = auth_service(Arc::new(InMemoryUserRepository::new()));
+        auth.create_user("alice", "correct horse 1", Role::Reader)
+            .unwrap();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        for _ in 0..ThrottlePolicy::PER_USERNAME.free_failures + 1 {
+            assert!(matches!(
+                auth.change_password("alice", "guess", "new horse 22", ip),
+                Err(AuthError::BadPassword)
+            ));
+        }
+        // Even the right password has to wait now, on every form.
+        assert!(matches!(
+            auth.change_password("alice", "correct horse 1", "new horse 22", ip),
+            Err(AuthError::TooManyAttempts(_))
+        ));
+        assert!(matches!(
+            auth.delete_account("alice", "correct horse 1", ip),
+            Err(AuthError::TooManyAttempts(_))
+        ));
+        assert!(matches!(
+            auth.login(&credentials("alice", "correct horse 1"), ip),
+            Err(AuthError::TooManyAttempts(_))
+        ));
+        assert!(auth
+            .authenticate(&credentials("alice", "correct horse 1"))
+            .is_ok());
+    }
+
+    #[test]
+    fn a_right_password_on_the_account_page_clears_failures() {
+        let auth = auth_service(Arc::new(InMemoryUserRepository::new()));
+        auth.create_user("alice", "correct horse 1", Role::Reader)
+            .unwrap();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        for _ in 0..ThrottlePolicy::PER_USERNAME.free_failures {
+            assert!(auth.delete_account("alice", "guess", ip).is_err());
+        }
+        auth.change_password("alice", "correct horse 1", "new horse 22", ip)
+            .unwrap();
+        for _ in 0..ThrottlePolicy::PER_USERNAME.free_failures {
+            assert!(matches!(
+                auth.delete_account("alice", "guess", ip),
+                Err(AuthError::BadPassword)
+            ));
+        }
+        auth.delete_account("alice", "new horse 22", ip).unwrap();
+        assert!(matches!(
+            auth.authenticate(&credentials("alice", "new horse 22")),
+            Err(AuthError::UnknownUser)
+        ));
     }
 }
@@ -0,0 +1,410 @@
+use crate::models::book::Book;
+use crate::services::ink_importer::parse_ink_json;
+use crate::services::markdown_importer::parse_markdown;
+use crate::services::twine_importer::{parse_twee, parse_twine_html, TwineError};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct BookLoadError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for BookLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// Something in an imported story that could not be carried over into the
+/// book, such as a macro we cannot run.
+#[derive(Debug, Clone)]
+pub struct ImportWarning {
+    pub line: Option<usize>,
+    /// The passage, knot or section the warning is about.
+    pub passage: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct LoadedLibrary {
+    pub books: Vec<Book>,
+    pub errors: Vec<BookLoadError>,
+    /// What each imported book left out, by book id.
+    pub warnings: HashMap<u32, Vec<ImportWarning>>,
+}
+
+/// Reads every `.json`, `.toml`, `.yaml` and `.yml` file in `dir` as a single `Book`,
+/// `.json` files compiled by inklecate as Ink stories, `.md` and `.markdown`
+/// files as Markdown stories, `.twee` and `.tw` files as Twee 3 and `.html`
+/// files as Twine 2 stories or archives. Files that fail to parse are reported in `errors` and skipped,
+/// as are books whose id an earlier file already used, since readers' progress is kept by book id.
+pub fn load_library(dir: &Path) -> LoadedLibrary {
+    let mut library = LoadedLibrary::default();
+    let mut sources = HashMap::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            library.errors.push(BookLoadError {
+                path: dir.to_path_buf(),
+                line: None,
+                message: format!("Failed to read story directory: {}", e),
+            });
+            return library;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    for path in paths {
+        let Some(extension) = path.extension().and_then(|e| e.to_str()) else {
+            continue;
+        };
+        let result = match extension {
+            "json" => read_source(&path).and_then(|source| {
+                if is_compiled_ink(&source) {
+                    parse_ink_json(&source, &story_name(&path))
+                        .map(|import| (import.book, import.warnings))
+                        .map_err(|e| BookLoadError {
+                            path: path.to_path_buf(),
+                            line: None,
+                            message: e.to_string(),
+                        })
+                } else {
+                    parse_json(&path, &source).map(|book| (book, Vec::new()))
+                }
+            }),
+            "toml" => read_source(&path)
+                .and_then(|source| parse_toml(&path, &source))
+                .map(|book| (book, Vec::new())),
+            "yaml" | "yml" => read_source(&path)
+                .and_then(|source| parse_yaml(&path, &source))
+                .map(|book| (book, Vec::new())),
+            "twee" | "tw" => read_source(&path)
+                .and_then(|source| parse_twee(&source).map_err(|e| twine_error(&path, e)))
+                .map(|import| (import.book, import.warnings)),
+            "html" => {
+                match read_source(&path)
+                    .and_then(|source| parse_twine_html(&source).map_err(|e| twine_error(&path, e)))
+                {
+                    Ok(imports) => {
+                        for import in imports {
+                            add_book(
+                                &mut library,
+                                &mut sources,
+                                &path,
+                                import.book,
+                                import.warnings,
+                            );
+                        }
+                    }
+                    Err(e) => library.errors.push(e),
+                }
+                continue;
+            }
+            "md" | "markdown" => read_source(&path).and_then(|source| {
+                parse_markdown(&source)
+                    .map(|book| (book, Vec::new()))
+                    .map_err(|e| BookLoadError {
+                        path: path.to_path_buf(),
+                        line: e.line,
+                        message: e.message,
+                    })
+            }),
+            "ink" => Err(BookLoadError {
+                path: path.to_path_buf(),
+                line: None,
+                message: "Ink stories must be compiled to JSON with inklecate or Inky first"
+                    .to_string(),
+            }),
+            _ => continue,
+        };
+        match result {
+            Ok((book, warnings)) => add_book(&mut library, &mut sources, &path, book, warnings),
+            Err(e) => library.errors.push(e),
+        }
+    }
+
+    library
+}
+
+/// Adds `book` from `path`, with what its import left out, unless a book
+/// with its id came from another file first, keeping track of which file
+/// each id came from in `sources`.
+fn add_book(
+    library: &mut LoadedLibrary,
+    sources: &mut HashMap<u32, PathBuf>,
+    path: &Path,
+    book: Book,
+    warnings: Vec<ImportWarning>,
+) {
+    if let Some(first) = sources.get(&book.id) {
+        library.errors.push(BookLoadError {
+            path: path.to_path_buf(),
+            line: None,
+            message: format!(
+                "book id {} of \"{}\" is already used by {}",
+                book.id,
+                book.title,
+                first.display()
+            ),
+        });
+        return;
+    }
+    sources.insert(book.id, path.to_path_buf());
+    if !warnings.is_empty() {
+        library.warnings.insert(book.id, warnings);
+    }
+    library.books.push(book);
+}
+
+fn read_source(path: &Path) -> Result<String, BookLoadError> {
+    fs::read_to_string(path).map_err(|e| BookLoadError {
+        path: path.to_path_buf(),
+        line: None,
+        message: format!("Failed to read file: {}", e),
+    })
+}
+
+fn parse_json(path: &Path, source: &str) -> Result<Book, BookLoadError> {
+    serde_json::from_str(source).map_err(|e| BookLoadError {
+        path: path.to_path_buf(),
+        line: Some(e.line()),
+        message: e.to_string(),
+    })
+}
+
+fn parse_toml(path: &Path, source: &str) -> Result<Book, BookLoadError> {
+    toml::from_str(source).map_err(|e| BookLoadError {
+        path: path.to_path_buf(),
+        line: e
+            .span()
+            .map(|span| source[..span.start].matches('\n').count() + 1),
+        message: e.message().to_string(),
+    })
+}
+
+fn parse_yaml(path: &Path, source: &str) -> Result<Book, BookLoadError> {
+    serde_yaml::from_str(source).map_err(|e| BookLoadError {
+        path: path.to_path_buf(),
+        line: e.location().map(|location| location.line()),
+        message: e.to_string(),
+    })
+}
+
+/// inklecate writes `inkVersion` first, which a `Book` never has.
+fn is_compiled_ink(source: &str) -> bool {
+    source
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .strip_prefix('{')
+        .is_some_and(|rest| rest.trim_start().starts_with("\"inkVersion\""))
+}
+
+/// The file name without extensions, as in `lighthouse` for
+/// `lighthouse.ink.json`.
+fn story_name(path: &Path) -> String {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .unwrap_or("Untitled")
+        .to_string()
+}
+
+fn twine_error(path: &Path, e: TwineError) -> BookLoadError {
+    BookLoadError {
+        path: path.to_path_buf(),
+        line: e.line,
+        message: e.message,
+    }
+}
+
+/// A stable book id for imported stories that have no number of their own,
+/// taken from the start of the SHA-256 of `name`.
+pub fn id_from_name(name: &str) -> u32 {
+    let digest = Sha256::digest(name.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+/// Trims each line and the text, and collapses the blank lines left behind
+/// by markup removed from imported stories.
+pub fn tidy_content(content: &str) -> String {
+    let mut tidy = String::new();
+    let mut blank = false;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() {
+            blank = !tidy.is_empty();
+            continue;
+        }
+        if blank {
+            tidy.push_str("\n\n");
+        } else if !tidy.is_empty() {
+            tidy.push('\n');
+        }
+        tidy.push_str(line);
+        blank = false;
+    }
+    tidy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::book_service::fixtures::generate_fake_library;
+
+    /// An empty directory of its own for each test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mustachestory-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Loads `source` as the only file in a directory, named `file_name`.
+    fn load_one(name: &str, file_name: &str, source: &str) -> LoadedLibrary {
+        let dir = temp_dir(name);
+        fs::write(dir.join(file_name), source).unwrap();
+        let library = load_library(&dir);
+        let _ = fs::remove_dir_all(&dir);
+        library
+    }
+
+    fn as_json(book: &Book) -> serde_json::Value {
+        serde_json::to_value(book).unwrap()
+    }
+
+    #[test]
+    fn loads_json_toml_and_yaml_books() {
+        let [mansion, station] = generate_fake_library().try_into().unwrap();
+        let mut yaml_mansion = mansion.clone();
+        yaml_mansion.id = 3;
+        let dir = temp_dir("formats");
+        fs::write(
+            dir.join("a.json"),
+            serde_json::to_string_pretty(&mansion).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("b.toml"), toml::to_string(&station).unwrap()).unwrap();
+        fs::write(
+            dir.join("c.yaml"),
+            serde_yaml::to_string(&yaml_mansion).unwrap(),
+        )
+        .unwrap();
+
+        let library = load_library(&dir);
+        assert!(library.errors.is_empty(), "{:?}", library.errors);
+        let loaded: Vec<_> = library.books.iter().map(as_json).collect();
+        assert_eq!(
+            loaded,
+            [as_json(&mansion), as_json(&station), as_json(&yaml_mansion)]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_a_second_book_with_the_same_id() {
+        let [mansion, _] = generate_fake_library().try_into().unwrap();
+        let dir = temp_dir("duplicate-id");
+        fs::write(dir.join("a.json"), serde_json::to_string(&mansion).unwrap()).unwrap();
+        fs::write(
+            dir.join("b.md"),
+            "---\ntitle: Copycat\nid: 1\n---\n# Start\nHello.\n",
+        )
+        .unwrap();
+        fs::write(dir.join("c.yaml"), serde_yaml::to_string(&mansion).unwrap()).unwrap();
+
+        let library = load_library(&dir);
+        let titles: Vec<_> = library.books.iter().map(|book| &book.title).collect();
+        assert_eq!(titles, ["The Haunted Mansion"]);
+        let errors: Vec<_> = library.errors.iter().map(ToString::to_string).collect();
+        let first = dir.join("a.json");
+        assert_eq!(
+            errors,
+            [
+                format!(
+                    "{}: book id 1 of \"Copycat\" is already used by {}",
+                    dir.join("b.md").display(),
+                    first.display()
+                ),
+                format!(
+                    "{}: book id 1 of \"The Haunted Mansion\" is already used by {}",
+                    dir.join("c.yaml").display(),
+                    first.display()
+                ),
+            ]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reports_the_line_of_a_json_error() {
+        let library = load_one(
+            "json-error",
+            "broken.json",
+            "{\n  \"id\": 1,\n  \"title\": 7,\n  \"summary\": \"\"\n}",
+        );
+        assert!(library.books.is_empty());
+        assert_eq!(library.errors[0].line, Some(3));
+        assert!(library.errors[0].path.ends_with("broken.json"));
+    }
+
+    #[test]
+    fn reports_the_line_of_a_toml_error() {
+        let library = load_one(
+            "toml-error",
+            "broken.toml",
+            "id = 1\ntitle = \"T\"\nsummary = \"\"\nstarting_page = \"one\"\npages = []\n",
+        );
+        assert!(library.books.is_empty());
+        assert_eq!(library.errors[0].line, Some(4));
+        assert!(library.errors[0].to_string().contains("broken.toml:4:"));
+    }
+
+    #[test]
+    fn reports_the_line_of_a_yaml_error() {
+        let library = load_one(
+            "yaml-error",
+            "broken.yaml",
+            "id: 1\ntitle: T\nsummary: ''\nstarting_page: 1\npages:\n  - id: one\n",
+        );
+        assert!(library.books.is_empty());
+        assert_eq!(library.errors[0].line, Some(6));
+    }
+
+    #[test]
+    fn skips_files_it_does_not_know() {
+        let dir = temp_dir("unknown");
+        fs::write(dir.join("notes.txt"), "not a story").unwrap();
+        fs::write(dir.join("README"), "not a story either").unwrap();
+        fs::write(dir.join("draft.ink"), "Hello -> END").unwrap();
+        fs::create_dir(dir.join("drafts.json")).unwrap();
+
+        let library = load_library(&dir);
+        assert!(library.books.is_empty());
+        assert_eq!(library.errors.len(), 1);
+        assert!(library.errors[0].path.ends_with("draft.ink"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reports_a_missing_directory() {
+        let library = load_library(Path::new("/nonexistent/mustachestory/books"));
+        assert!(library.books.is_empty());
+        assert_eq!(library.errors.len(), 1);
+        assert_eq!(library.errors[0].line, None);
+    }
+}
@@ -1,15 +1,38 @@
-use crate::models::book::{Book, Choice, Page};
+use crate::models::book::{Book, Page};
+use crate::services::book_loader::{load_library, BookLoadError, ImportWarning};
+use crate::services::book_validator::{validate_book, ValidationReport};
+use std::collections::HashMap;
+use std::path::Path;
 
 #[derive(Clone)]
 pub struct BookService {
     library: Vec<Book>,
+    reports: Vec<ValidationReport>,
 }
 
 impl BookService {
-    pub fn new() -> Self {
-        Self {
-            library: Self::generate_fake_library(),
+    /// Validates every book, keeping the ones without errors. Books with
+    /// errors are refused but still appear in `get_validation_reports`,
+    /// which also lists what the import of each book left out, from
+    /// `warnings` by book id.
+    pub fn new(books: Vec<Book>, mut warnings: HashMap<u32, Vec<ImportWarning>>) -> Self {
+        let mut library = Vec::new();
+        let mut reports = Vec::new();
+        for book in books {
+            let mut report = validate_book(&book);
+            let left_out = warnings.remove(&book.id).unwrap_or_default();
+            report.issues.extend(left_out.into_iter().map(Into::into));
+            if !report.has_errors() {
+                library.push(book);
+            }
+            reports.push(report);
         }
+        Self { library, reports }
+    }
+
+    pub fn from_dir(dir: &Path) -> (Self, Vec<BookLoadError>) {
+        let loaded = load_library(dir);
+        (Self::new(loaded.books, loaded.warnings), loaded.errors)
     }
 
     pub fn get_book(&self, book_id: u32) -> Option<&Book> {
@@ -30,13 +53,32 @@ impl BookService {
         &self.library
     }
 
-    fn generate_fake_library() -> Vec<Book> {
+    pub fn get_validation_reports(&self) -> &Vec<ValidationReport> {
+        &self.reports
+    }
+}
+
+// Implement thread safety traits
+unsafe impl Send for BookService {}
+unsafe impl Sync for BookService {}
+
+#[cfg(test)]
+pub mod fixtures {
+    use crate::models::book::{
+        Book, Choice, Condition, Effect, Ending, EndingKind, Page, UnmetChoice,
+    };
+    use crate::models::progress::PlaythroughState;
+
+    /// The two books the app shipped with before stories were loaded from
+    /// files, kept to test against. `books/` holds the same books.
+    pub fn generate_fake_library() -> Vec<Book> {
         vec![
             Book {
                 id: 1,
                 title: "The Haunted Mansion".to_string(),
                 summary: "Explore a spooky mansion full of secrets".to_string(),
                 starting_page: 101,
+                initial_state: PlaythroughState::default(),
                 pages: vec![
                     Page {
                         id: 101,
@@ -45,12 +87,19 @@ impl BookService {
                             Choice {
                                 text: "Enter through the front door".to_string(),
                                 target_page_id: 102,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Sneak around to the back".to_string(),
                                 target_page_id: 103,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 102,
@@ -60,12 +109,19 @@ impl BookService {
                             Choice {
                                 text: "Light a match and explore".to_string(),
                                 target_page_id: 104,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Feel your way in the dark".to_string(),
                                 target_page_id: 105,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 103,
@@ -74,54 +130,85 @@ impl BookService {
                             Choice {
                                 text: "Climb through carefully".to_string(),
                                 target_page_id: 106,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Look for another way in".to_string(),
                                 target_page_id: 101,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 104,
-                        content: "The match flickers, revealing a grand staircase. Do you:".to_string(),
+                        content: "The match flickers, revealing a grand staircase. Do you:"
+                            .to_string(),
                         choices: vec![
                             Choice {
                                 text: "Go upstairs".to_string(),
                                 target_page_id: 107,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Check the parlor".to_string(),
                                 target_page_id: 108,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 105,
-                        content: "You stumble in the dark and hear a creak behind you. Do you:".to_string(),
+                        content: "You stumble in the dark and hear a creak behind you. Do you:"
+                            .to_string(),
                         choices: vec![
                             Choice {
                                 text: "Turn around slowly".to_string(),
                                 target_page_id: 109,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Run forward blindly".to_string(),
                                 target_page_id: 110,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 106,
-                        content: "You're in a dusty kitchen. A rat scurries by. Do you:".to_string(),
+                        content: "You're in a dusty kitchen. A rat scurries by. Do you:"
+                            .to_string(),
                         choices: vec![
                             Choice {
                                 text: "Search the cabinets".to_string(),
                                 target_page_id: 111,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Exit through the pantry".to_string(),
                                 target_page_id: 112,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 107,
@@ -130,26 +217,121 @@ impl BookService {
                             Choice {
                                 text: "Enter the left door".to_string(),
                                 target_page_id: 113,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Enter the right door".to_string(),
                                 target_page_id: 114,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 108,
-                        content: "The parlor has a strange painting. It seems to be watching you. Do you:".to_string(),
+                        content: "The parlor has a strange painting. It seems to be watching you. Do you:"
+                            .to_string(),
                         choices: vec![
                             Choice {
                                 text: "Examine the painting".to_string(),
                                 target_page_id: 115,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Ignore it and look around".to_string(),
                                 target_page_id: 116,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
+                    },
+                    Page {
+                        id: 109,
+                        content: "You turn to find the butler's ghost offering you tea. You spend a pleasant eternity in the mansion."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Neutral,
+                            title: "Tea for Eternity".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 110,
+                        content: "You run straight through a rotten floorboard and fall into the cellar. The door slams shut above you."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Death,
+                            title: "Swallowed by the Cellar".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 111,
+                        content: "Behind the soup tins you find the deed to the mansion, made out in your name."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Victory,
+                            title: "Lord of the Manor".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 112,
+                        content: "The pantry door opens onto a staircase going down. Far below, something is humming your name..."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Cliffhanger,
+                            title: "The Humming Below".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 113,
+                        content: "The left door opens onto a nursery where a music box plays by itself. You wake up at home, clutching the key."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Neutral,
+                            title: "A Dream or a Memory".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 114,
+                        content: "The right door leads to the attic, where you find the lost family jewels."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Victory,
+                            title: "The Attic Treasure".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 115,
+                        content: "You lean closer to the painting and it pulls you in. Now you watch the next visitor from inside the frame."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Death,
+                            title: "Framed".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 116,
+                        content: "You find a candle, a letter and a map of secret passages. To be continued..."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Cliffhanger,
+                            title: "The Secret Passages".to_string(),
+                        }),
                     },
                 ],
             },
@@ -158,6 +340,7 @@ impl BookService {
                 title: "Space Station Omega".to_string(),
                 summary: "A sci-fi adventure in deep space".to_string(),
                 starting_page: 201,
+                initial_state: PlaythroughState::default(),
                 pages: vec![
                     Page {
                         id: 201,
@@ -166,28 +349,43 @@ impl BookService {
                             Choice {
                                 text: "Head to the control room".to_string(),
                                 target_page_id: 202,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Check the engineering bay".to_string(),
                                 target_page_id: 203,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 202,
-                        content:
-                            "You reach the control room. The main console is sparking! Do you:"
-                                .to_string(),
+                        content: "You reach the control room. The main console is sparking! Do you:"
+                            .to_string(),
                         choices: vec![
                             Choice {
                                 text: "Attempt to repair it".to_string(),
                                 target_page_id: 204,
+                                conditions: vec![Condition::HasItem {
+                                    item: "toolkit".to_string(),
+                                }],
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Disabled,
                             },
                             Choice {
                                 text: "Call for help on the comms".to_string(),
                                 target_page_id: 205,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
                     },
                     Page {
                         id: 203,
@@ -196,12 +394,68 @@ impl BookService {
                             Choice {
                                 text: "Try to seal the leak".to_string(),
                                 target_page_id: 206,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
                             },
                             Choice {
                                 text: "Evacuate the area".to_string(),
                                 target_page_id: 207,
+                                conditions: Vec::new(),
+                                effects: Vec::new(),
+                                when_unmet: UnmetChoice::Hidden,
+                            },
+                            Choice {
+                                text: "Grab a toolkit and run to the control room".to_string(),
+                                target_page_id: 202,
+                                conditions: Vec::new(),
+                                effects: vec![Effect::AddItem {
+                                    item: "toolkit".to_string(),
+                                }],
+                                when_unmet: UnmetChoice::Hidden,
                             },
                         ],
+                        ending: None,
+                    },
+                    Page {
+                        id: 204,
+                        content: "With a few sparks and a lot of swearing, the console comes back online. The station is saved."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Victory,
+                            title: "Chief Engineer".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 205,
+                        content: "A patrol ship answers and tows the station to safety. You get a footnote in the report."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Neutral,
+                            title: "Rescued".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 206,
+                        content: "You seal the leak just as the coolant freezes your gloves to the pipe. The station's reactor stabilises behind you."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Victory,
+                            title: "Cold Hands, Warm Heart".to_string(),
+                        }),
+                    },
+                    Page {
+                        id: 207,
+                        content: "You evacuate the bay and seal the door. Through the window you see a shape moving in the coolant mist..."
+                            .to_string(),
+                        choices: Vec::new(),
+                        ending: Some(Ending {
+                            kind: EndingKind::Cliffhanger,
+                            title: "Something in the Mist".to_string(),
+                        }),
                     },
                 ],
             },
@@ -209,6 +463,70 @@ impl BookService {
     }
 }
 
-// Implement thread safety traits
-unsafe impl Send for BookService {}
-unsafe impl Sync for BookService {}
+#[cfg(test)]
+mod tests {
+    use super::fixtures::generate_fake_library;
+    use super::*;
+
+    #[test]
+    fn the_shipped_books_match_the_fake_library() {
+        let (service, errors) = BookService::from_dir(Path::new("books"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            serde_json::to_value(service.get_library()).unwrap(),
+            serde_json::to_value(generate_fake_library()).unwrap()
+        );
+        assert!(service
+            .get_validation_reports()
+            .iter()
+            .all(|report| report.is_clean()));
+    }
+
+    #[test]
+    fn looks_up_books_and_pages() {
+        let service = BookService::new(generate_fake_library(), HashMap::new());
+        assert_eq!(service.get_book(2).unwrap().title, "Space Station Omega");
+        assert!(service.get_book(3).is_none());
+        assert_eq!(service.get_starting_page(1).unwrap().id, 101);
+        assert!(service.get_page(1, 111).unwrap().ending.is_some());
+        assert!(service.get_page(2, 111).is_none());
+    }
+
+    #[test]
+    fn reports_what_an_import_left_out() {
+        let dir = std::env::temp_dir().join(format!(
+            "mustachestory-import-report-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("cellar.twee"),
+            ":: StoryTitle\nThe Cellar\n\n:: Start\nA door. <<set $seen to true>>\n[[Down]]\n\n:: Down [death]\nDark.\n",
+        )
+        .unwrap();
+
+        let (service, errors) = BookService::from_dir(&dir);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(service.get_library().len(), 1);
+        let report = &service.get_validation_reports()[0];
+        assert!(!report.has_errors());
+        let issues: Vec<_> = report.issues.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            issues,
+            ["line 5, in \"Start\": unsupported macro <<set $seen to true>>"]
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn refuses_books_with_errors_but_reports_them() {
+        let mut books = generate_fake_library();
+        books[1].starting_page = 999;
+        let service = BookService::new(books, HashMap::new());
+        assert_eq!(service.get_library().len(), 1);
+        assert!(service.get_book(2).is_none());
+        assert_eq!(service.get_validation_reports().len(), 2);
+        assert!(service.get_validation_reports()[1].has_errors());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::models::book::Book;
+use crate::services::book_loader::ImportWarning;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The book cannot be served.
+    Error,
+    /// The book is served but readers may hit a broken path.
+    Warning,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValidationIssue {
+    MissingStartingPage {
+        starting_page: u32,
+    },
+    DuplicatePageId {
+        page_id: u32,
+    },
+    DanglingChoice {
+        page_id: u32,
+        choice_text: String,
+        target_page_id: u32,
+    },
+    UnreachablePage {
+        page_id: u32,
+    },
+    DeadEnd {
+        page_id: u32,
+    },
+    /// Something in an imported story that the book leaves out, such as a
+    /// Twine macro.
+    LeftOut {
+        passage: String,
+        line: Option<usize>,
+        message: String,
+    },
+}
+
+impl ValidationIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ValidationIssue::MissingStartingPage { .. }
+            | ValidationIssue::DuplicatePageId { .. } => Severity::Error,
+            ValidationIssue::DanglingChoice { .. }
+            | ValidationIssue::UnreachablePage { .. }
+            | ValidationIssue::DeadEnd { .. }
+            | ValidationIssue::LeftOut { .. } => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationIssue::MissingStartingPage { starting_page } => {
+                write!(f, "starting page {} does not exist", starting_page)
+            }
+            ValidationIssue::DuplicatePageId { page_id } => {
+                write!(f, "page id {} is used more than once", page_id)
+            }
+            ValidationIssue::DanglingChoice {
+                page_id,
+                choice_text,
+                target_page_id,
+            } => write!(
+                f,
+                "page {} choice \"{}\" points at missing page {}",
+                page_id, choice_text, target_page_id
+            ),
+            ValidationIssue::UnreachablePage { page_id } => {
+                write!(
+                    f,
+                    "page {} cannot be reached from the starting page",
+                    page_id
+                )
+            }
+            ValidationIssue::DeadEnd { page_id } => {
+                write!(f, "page {} has no choices and is not an ending", page_id)
+            }
+            ValidationIssue::LeftOut {
+                passage,
+                line: Some(line),
+                message,
+            } => write!(f, "line {}, in \"{}\": {}", line, passage, message),
+            ValidationIssue::LeftOut {
+                passage,
+                line: None,
+                message,
+            } => write!(f, "in \"{}\": {}", passage, message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub book_id: u32,
+    pub title: String,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues
+            .iter()
+            .any(|issue| issue.severity() == Severity::Error)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "book {} \"{}\"", self.book_id, self.title)?;
+        for issue in &self.issues {
+            write!(f, "\n  {:?}: {}", issue.severity(), issue)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_book(book: &Book) -> ValidationReport {
+    let mut issues = Vec::new();
+
+    let mut seen = HashSet::new();
+    for page in &book.pages {
+        if !seen.insert(page.id) {
+            issues.push(ValidationIssue::DuplicatePageId { page_id: page.id });
+        }
+    }
+
+    if !seen.contains(&book.starting_page) {
+        issues.push(ValidationIssue::MissingStartingPage {
+            starting_page: book.starting_page,
+        });
+    }
+
+    for page in &book.pages {
+        for choice in &page.choices {
+            if !seen.contains(&choice.target_page_id) {
+                issues.push(ValidationIssue::DanglingChoice {
+                    page_id: page.id,
+                    choice_text: choice.text.clone(),
+                    target_page_id: choice.target_page_id,
+                });
+            }
+        }
+        if page.choices.is_empty() && page.ending.is_none() {
+            issues.push(ValidationIssue::DeadEnd { page_id: page.id });
+        }
+    }
+
+    let reachable = reachable_pages(book);
+    let mut reported = HashSet::new();
+    for page in &book.pages {
+        if !reachable.contains(&page.id) && reported.insert(page.id) {
+            issues.push(ValidationIssue::UnreachablePage { page_id: page.id });
+        }
+    }
+
+    ValidationReport {
+        book_id: book.id,
+        title: book.title.clone(),
+        issues,
+    }
+}
+
+impl From<ImportWarning> for ValidationIssue {
+    fn from(warning: ImportWarning) -> Self {
+        ValidationIssue::LeftOut {
+            passage: warning.passage,
+            line: warning.line,
+            message: warning.message,
+        }
+    }
+}
+
+fn reachable_pages(book: &Book) -> HashSet<u32> {
+    let pages: HashMap<u32, _> = book.pages.iter().map(|p| (p.id, p)).collect();
+    let mut reachable = HashSet::new();
+    let mut queue = VecDeque::new();
+
+    if pages.contains_key(&book.starting_page) {
+        reachable.insert(book.starting_page);
+        queue.push_back(book.starting_page);
+    }
+
+    while let Some(page_id) = queue.pop_front() {
+        for choice in &pages[&page_id].choices {
+            if pages.contains_key(&choice.target_page_id) && reachable.insert(choice.target_page_id)
+            {
+                queue.push_back(choice.target_page_id);
+            }
+        }
+    }
+
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A book starting on `starting_page` whose pages are given as
+    /// `(id, [target ids])`; pages without choices are endings.
+    fn book(starting_page: u32, pages: &[(u32, &[u32])]) -> Book {
+        let pages: Vec<_> = pages
+            .iter()
+            .map(|(id, targets)| {
+                let choices: Vec<_> = targets
+                    .iter()
+                    .map(|target| json!({ "text": format!("To {}", target), "target_page_id": target }))
+                    .collect();
+                let ending = targets
+                    .is_empty()
+                    .then(|| json!({ "kind": "neutral", "title": "The end" }));
+                json!({ "id": id, "content": "", "choices": choices, "ending": ending })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "id": 1,
+            "title": "Test",
+            "summary": "",
+            "starting_page": starting_page,
+            "pages": pages,
+        }))
+        .unwrap()
+    }
+
+    fn issues(report: &ValidationReport) -> Vec<String> {
+        report.issues.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn a_connected_book_is_clean() {
+        let report = validate_book(&book(1, &[(1, &[2, 3]), (2, &[3]), (3, &[])]));
+        assert!(report.is_clean());
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn warns_about_unreachable_pages() {
+        let report = validate_book(&book(1, &[(1, &[2]), (2, &[]), (3, &[4]), (4, &[3])]));
+        assert_eq!(
+            issues(&report),
+            [
+                "page 3 cannot be reached from the starting page",
+                "page 4 cannot be reached from the starting page",
+            ]
+        );
+        assert!(!report.has_errors());
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn warns_about_dangling_choices() {
+        let report = validate_book(&book(1, &[(1, &[2, 9]), (2, &[])]));
+        assert_eq!(
+            issues(&report),
+            ["page 1 choice \"To 9\" points at missing page 9"]
+        );
+        assert_eq!(report.issues[0].severity(), Severity::Warning);
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn warns_about_dead_ends() {
+        let mut book = book(1, &[(1, &[2]), (2, &[])]);
+        book.pages[1].ending = None;
+        let report = validate_book(&book);
+        assert_eq!(
+            issues(&report),
+            ["page 2 has no choices and is not an ending"]
+        );
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn a_missing_starting_page_is_an_error() {
+        let report = validate_book(&book(5, &[(1, &[2]), (2, &[])]));
+        assert_eq!(
+            issues(&report),
+            [
+                "starting page 5 does not exist",
+                "page 1 cannot be reached from the starting page",
+                "page 2 cannot be reached from the starting page",
+            ]
+        );
+        assert_eq!(report.issues[0].severity(), Severity::Error);
+        assert!(report.has_errors());
+    }
+
+    #[test]
+    fn duplicate_page_ids_are_an_error_reported_once() {
+        let report = validate_book(&book(
+            1,
+            &[(1, &[2]), (2, &[]), (2, &[]), (3, &[]), (3, &[])],
+        ));
+        assert_eq!(
+            issues(&report),
+            [
+                "page id 2 is used more than once",
+                "page id 3 is used more than once",
+                "page 3 cannot be reached from the starting page",
+            ]
+        );
+        assert!(report.has_errors());
+    }
+
+    #[test]
+    fn report_lists_issues_with_their_severity() {
+        let report = validate_book(&book(1, &[(1, &[1]), (1, &[])]));
+        assert_eq!(
+            report.to_string(),
+            "book 1 \"Test\"\n  Error: page id 1 is used more than once"
+        );
+    }
+}
@@ -0,0 +1,482 @@
+use crate::models::book::{Book, Choice, Condition, Effect, Page};
+use crate::services::site_exporter::{reachable_pages, ExportError};
+use crate::templates::render_markdown;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+const CONTAINER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#;
+
+const STYLESHEET: &str = "h1, h2 { text-align: center; }
+.section { page-break-before: always; break-before: page; }
+.choices { list-style: none; padding: 0; }
+.choices li { margin: 0.5em 0; }
+.ending { margin-top: 1em; text-align: center; font-style: italic; }
+";
+
+impl From<zip::result::ZipError> for ExportError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ExportError::Io(e.to_string())
+    }
+}
+
+/// Writes `book` to `out_file` as an EPUB 3 gamebook. Every page reachable
+/// from the starting page becomes a numbered section, with the starting
+/// page as section 1, and every choice a "turn to section N" link. With
+/// `shuffle` the other sections are numbered in a random order, as in
+/// printed gamebooks, so the next part of the story is not simply the next
+/// section. Conditions and effects are written out as instructions to the
+/// reader. Returns the number of sections written.
+pub fn export_epub(book: &Book, out_file: &Path, shuffle: bool) -> Result<usize, ExportError> {
+    let mut pages = reachable_pages(book);
+    if shuffle {
+        for i in (2..pages.len()).rev() {
+            let j = 1 + OsRng.next_u32() as usize % i;
+            pages.swap(i, j);
+        }
+    }
+    let sections: HashMap<u32, usize> = pages
+        .iter()
+        .enumerate()
+        .map(|(index, page)| (page.id, index + 1))
+        .collect();
+
+    let mut zip = ZipWriter::new(File::create(out_file)?);
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The mimetype must come first and uncompressed for readers to
+    // recognise the archive.
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(CONTAINER.as_bytes())?;
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(package(book, pages.len()).as_bytes())?;
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(navigation(book, pages.len()).as_bytes())?;
+    zip.start_file("OEBPS/style.css", deflated)?;
+    zip.write_all(STYLESHEET.as_bytes())?;
+    zip.start_file("OEBPS/title.xhtml", deflated)?;
+    zip.write_all(title_page(book).as_bytes())?;
+    for (index, page) in pages.iter().enumerate() {
+        zip.start_file(format!("OEBPS/{}", section_file(index + 1)), deflated)?;
+        zip.write_all(section(book, page, index + 1, &sections).as_bytes())?;
+    }
+    zip.finish()?;
+
+    Ok(pages.len())
+}
+
+fn section_file(number: usize) -> String {
+    format!("section-{}.xhtml", number)
+}
+
+/// The package document: what the book is and which files it is made of,
+/// in reading order.
+fn package(book: &Book, sections: usize) -> String {
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    for number in 1..=sections {
+        manifest.push_str(&format!(
+            "    <item id=\"section-{0}\" href=\"{1}\" media-type=\"application/xhtml+xml\"/>\n",
+            number,
+            section_file(number)
+        ));
+        spine.push_str(&format!("    <itemref idref=\"section-{}\"/>\n", number));
+    }
+
+    let description = if book.summary.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "    <dc:description>{}</dc:description>\n",
+            escape(&book.summary)
+        )
+    };
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="book-id" xml:lang="en">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="book-id">urn:mustachestory:book:{id}</dc:identifier>
+    <dc:title>{title}</dc:title>
+{description}    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="style" href="style.css" media-type="text/css"/>
+    <item id="title" href="title.xhtml" media-type="application/xhtml+xml"/>
+{manifest}  </manifest>
+  <spine>
+    <itemref idref="title"/>
+{spine}  </spine>
+</package>
+"#,
+        id = book.id,
+        title = escape(&book.title),
+        description = description,
+        modified = utc_timestamp(SystemTime::now()),
+        manifest = manifest,
+        spine = spine,
+    )
+}
+
+/// The table of contents, which only lists the title page and the first
+/// section so as not to give the story away.
+fn navigation(book: &Book, sections: usize) -> String {
+    let start = if sections > 0 {
+        format!(
+            "\n      <li><a href=\"{}\">Section 1</a></li>",
+            section_file(1)
+        )
+    } else {
+        String::new()
+    };
+    xhtml(
+        &book.title,
+        &format!(
+            r#"<nav epub:type="toc" id="toc">
+    <h1>Contents</h1>
+    <ol>
+      <li><a href="title.xhtml">{}</a></li>{}
+    </ol>
+  </nav>"#,
+            escape(&book.title),
+            start
+        ),
+    )
+}
+
+fn title_page(book: &Book) -> String {
+    xhtml(
+        &book.title,
+        &format!(
+            "<h1>{}</h1>\n  <p>{}</p>\n  <p><a href=\"{}\">Begin at section 1.</a></p>",
+            escape(&book.title),
+            escape(&book.summary),
+            section_file(1)
+        ),
+    )
+}
+
+fn section(book: &Book, page: &Page, number: usize, sections: &HashMap<u32, usize>) -> String {
+    let mut body = format!(
+        "<section class=\"section\" id=\"section-{0}\">\n  <h2>{0}</h2>\n{1}",
+        number,
+        render_markdown(&page.content)
+    );
+    if let Some(ending) = &page.ending {
+        body.push_str(&format!(
+            "  <p class=\"ending\">The End: {}</p>\n",
+            escape(&ending.title)
+        ));
+    } else if !page.choices.is_empty() {
+        body.push_str("  <ul class=\"choices\">\n");
+        for choice in &page.choices {
+            if let Some(target) = sections.get(&choice.target_page_id) {
+                body.push_str(&format!("    <li>{}</li>\n", choice_line(choice, *target)));
+            }
+        }
+        body.push_str("  </ul>\n");
+    }
+    body.push_str("</section>");
+    xhtml(&format!("{} - {}", book.title, number), &body)
+}
+
+/// A choice as a printed gamebook puts it, e.g. "If you have the lantern:
+/// Climb the stairs — take the key, then turn to section 12."
+fn choice_line(choice: &Choice, target: usize) -> String {
+    let mut line = String::new();
+    if !choice.conditions.is_empty() {
+        let conditions: Vec<_> = choice.conditions.iter().map(condition_text).collect();
+        line.push_str(&format!("If {}: ", escape(&conditions.join(" and "))));
+    }
+    line.push_str(&escape(&choice.text));
+    line.push_str(" — ");
+    if !choice.effects.is_empty() {
+        let effects: Vec<_> = choice.effects.iter().map(effect_text).collect();
+        line.push_str(&format!("{}, then ", escape(&effects.join(", "))));
+    }
+    line.push_str(&format!(
+        "<a href=\"{}\">turn to section {}</a>.",
+        section_file(target),
+        target
+    ));
+    line
+}
+
+fn condition_text(condition: &Condition) -> String {
+    match condition {
+        Condition::FlagSet { flag } => format!("you have the codeword {}", flag),
+        Condition::FlagUnset { flag } => format!("you do not have the codeword {}", flag),
+        Condition::HasItem { item } => format!("you have the {}", item),
+        Condition::LacksItem { item } => format!("you do not have the {}", item),
+        Condition::CounterAtLeast { counter, value } => {
+            format!("your {} is at least {}", counter, value)
+        }
+        Condition::CounterBelow { counter, value } => {
+            format!("your {} is below {}", counter, value)
+        }
+    }
+}
+
+fn effect_text(effect: &Effect) -> String {
+    match effect {
+        Effect::SetFlag { flag } => format!("note the codeword {}", flag),
+        Effect::ClearFlag { flag } => format!("cross off the codeword {}", flag),
+        Effect::Increment { counter, by } if *by < 0 => {
+            format!("subtract {} from your {}", -by, counter)
+        }
+        Effect::Increment { counter, by } => format!("add {} to your {}", by, counter),
+        Effect::AddItem { item } => format!("take the {}", item),
+        Effect::RemoveItem { item } => format!("cross off the {}", item),
+    }
+}
+
+fn xhtml(title: &str, body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" xml:lang="en" lang="en">
+<head>
+  <title>{}</title>
+  <link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+  {}
+</body>
+</html>
+"#,
+        escape(title),
+        body
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `time` as the `CCYY-MM-DDThh:mm:ssZ` the `dcterms:modified` property
+/// needs.
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Read;
+    use std::time::Duration;
+    use zip::ZipArchive;
+
+    fn book() -> Book {
+        serde_json::from_value(json!({
+            "id": 1,
+            "title": "The Cellar",
+            "summary": "Down & out",
+            "starting_page": 1,
+            "pages": [
+                { "id": 1, "content": "A cellar door.", "choices": [
+                    { "text": "Go down", "target_page_id": 2 },
+                    { "text": "Fall through the floor", "target_page_id": 9 }
+                ] },
+                { "id": 2, "content": "Stairs.", "choices": [
+                    { "text": "Left", "target_page_id": 3 },
+                    { "text": "Right", "target_page_id": 4 }
+                ] },
+                { "id": 3, "content": "A lantern.", "choices": [
+                    { "text": "Take it", "target_page_id": 5,
+                      "effects": [{ "type": "add_item", "item": "lantern" }] }
+                ] },
+                { "id": 4, "content": "A wall.", "choices": [
+                    { "text": "Back", "target_page_id": 2 }
+                ] },
+                { "id": 5, "content": "Daylight.", "ending": { "kind": "victory", "title": "Out" } },
+                { "id": 6, "content": "Nobody gets here." }
+            ]
+        }))
+        .unwrap()
+    }
+
+    /// Exports `book` and reads every entry of the archive back, in order.
+    fn export(book: &Book, shuffle: bool, name: &str) -> (usize, Vec<(String, String)>, Vec<u8>) {
+        let out_file = std::env::temp_dir().join(format!(
+            "mustachestory-{}-{}.epub",
+            name,
+            std::process::id()
+        ));
+        let sections = export_epub(book, &out_file, shuffle).unwrap();
+        let bytes = std::fs::read(&out_file).unwrap();
+        let _ = std::fs::remove_file(&out_file);
+
+        let mut archive = ZipArchive::new(std::io::Cursor::new(bytes.clone())).unwrap();
+        let entries = (0..archive.len())
+            .map(|index| {
+                let mut file = archive.by_index(index).unwrap();
+                let mut content = String::new();
+                file.read_to_string(&mut content).unwrap();
+                (file.name().to_string(), content)
+            })
+            .collect();
+        (sections, entries, bytes)
+    }
+
+    fn entry<'a>(entries: &'a [(String, String)], name: &str) -> &'a str {
+        entries
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, content)| content.as_str())
+            .unwrap_or_else(|| panic!("{} is not in the archive", name))
+    }
+
+    /// The targets of every `href="..."` in `content`.
+    fn hrefs(content: &str) -> Vec<&str> {
+        content
+            .split("href=\"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn the_mimetype_comes_first_and_uncompressed() {
+        let (_, entries, bytes) = export(&book(), false, "epub-mimetype");
+        assert_eq!(
+            entries[0],
+            ("mimetype".to_string(), "application/epub+zip".to_string())
+        );
+
+        let mut archive = ZipArchive::new(std::io::Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(
+            archive.by_index(0).unwrap().compression(),
+            CompressionMethod::Stored
+        );
+        // Readers sniff the type at a fixed offset, so the local header may
+        // not carry any extra fields either.
+        assert_eq!(&bytes[..4], b"PK\x03\x04");
+        assert_eq!(&bytes[30..38], b"mimetype");
+        assert_eq!(&bytes[38..58], b"application/epub+zip");
+    }
+
+    #[test]
+    fn the_package_lists_every_section_in_the_manifest_and_spine() {
+        let (sections, entries, _) = export(&book(), false, "epub-package");
+        assert_eq!(sections, 5);
+        let package = entry(&entries, "OEBPS/content.opf");
+        for number in 1..=sections {
+            assert!(package.contains(&format!(
+                r#"<item id="section-{0}" href="section-{0}.xhtml" media-type="application/xhtml+xml"/>"#,
+                number
+            )));
+            assert!(package.contains(&format!(r#"<itemref idref="section-{}"/>"#, number)));
+        }
+        assert!(!package.contains("section-6"));
+        assert!(package.contains("<dc:description>Down &amp; out</dc:description>"));
+
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        for href in hrefs(package) {
+            assert!(
+                names.contains(&format!("OEBPS/{}", href).as_str()),
+                "{}",
+                href
+            );
+        }
+    }
+
+    #[test]
+    fn the_starting_page_is_always_section_one() {
+        for shuffle in [false, true] {
+            for _ in 0..10 {
+                let (_, entries, _) = export(&book(), shuffle, "epub-start");
+                assert!(entry(&entries, "OEBPS/section-1.xhtml").contains("A cellar door."));
+            }
+        }
+    }
+
+    #[test]
+    fn every_link_points_at_a_file_in_the_archive() {
+        for shuffle in [false, true] {
+            let (_, entries, _) = export(&book(), shuffle, "epub-links");
+            let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+            for (name, content) in entries.iter().filter(|(name, _)| name.ends_with(".xhtml")) {
+                for href in hrefs(content) {
+                    let target = format!("OEBPS/{}", href.split('#').next().unwrap());
+                    assert!(
+                        names.contains(&target.as_str()),
+                        "{} links to {}",
+                        name,
+                        href
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn choices_turn_to_their_section_and_missing_pages_are_dropped() {
+        let (_, entries, _) = export(&book(), false, "epub-choices");
+        let start = entry(&entries, "OEBPS/section-1.xhtml");
+        assert!(start.contains(r#"Go down — <a href="section-2.xhtml">turn to section 2</a>."#));
+        assert!(!start.contains("Fall through the floor"));
+        assert!(entry(&entries, "OEBPS/section-3.xhtml").contains(
+            r#"Take it — take the lantern, then <a href="section-5.xhtml">turn to section 5</a>."#
+        ));
+        assert!(entry(&entries, "OEBPS/section-5.xhtml")
+            .contains(r#"<p class="ending">The End: Out</p>"#));
+    }
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(1709210096)),
+            "2024-02-29T12:34:56Z"
+        );
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(946684799)),
+            "1999-12-31T23:59:59Z"
+        );
+    }
+}
@@ -0,0 +1,1071 @@
+use crate::models::book::{Book, Choice, Condition, Effect, Ending, EndingKind, Page, UnmetChoice};
+use crate::models::progress::PlaythroughState;
+use crate::services::book_loader::{id_from_name, tidy_content, ImportWarning};
+use serde_json::Value as Json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+/// Compiled story formats written by inklecate 1.0 and later.
+const INK_VERSIONS: std::ops::RangeInclusive<u64> = 19..=21;
+/// Guards against stories whose flow loops without reaching a choice.
+const MAX_STEPS_PER_PAGE: usize = 100_000;
+const MAX_PAGES: usize = 10_000;
+
+const HAS_CONDITION: u64 = 0x1;
+const HAS_START_CONTENT: u64 = 0x2;
+const HAS_CHOICE_ONLY_CONTENT: u64 = 0x4;
+const IS_INVISIBLE_DEFAULT: u64 = 0x8;
+
+/// A story read from compiled Ink, with whatever could not be carried over.
+#[derive(Debug)]
+pub struct InkImport {
+    pub book: Book,
+    pub warnings: Vec<ImportWarning>,
+}
+
+#[derive(Debug)]
+pub struct InkError(pub String);
+
+impl fmt::Display for InkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Reads a story compiled to JSON by inklecate or Inky.
+///
+/// Ink runs a story as a program, while a book is a fixed graph of pages, so
+/// the story is walked from its start: the text up to each set of choices
+/// becomes a page and every choice leads to the page its content starts.
+/// Global variables become the reader's flags (`true`/`false`) and counters
+/// (`+`/`-`), with choice conditions and assignments carried over as
+/// `Condition`s and `Effect`s. Whatever else the story computes at runtime,
+/// such as printed variables, conditional text, sequences, functions and
+/// tunnels, is left out and reported. Once-only choices stay available like
+/// sticky ones.
+///
+/// The `# title: ...` tag at the top of the story names the book, falling
+/// back to `name`; passages ending with `-> END` or `-> DONE` are endings,
+/// of the kind given by a `# victory`, `# death` or `# cliffhanger` tag.
+pub fn parse_ink_json(source: &str, name: &str) -> Result<InkImport, InkError> {
+    let json: Json = serde_json::from_str(source.trim_start_matches('\u{feff}'))
+        .map_err(|e| InkError(format!("line {}: {}", e.line(), e)))?;
+    let version = json["inkVersion"]
+        .as_u64()
+        .ok_or_else(|| InkError("missing inkVersion".to_string()))?;
+    if !INK_VERSIONS.contains(&version) {
+        return Err(InkError(format!(
+            "ink version {} is not supported, recompile with inklecate 1.0 or later",
+            version
+        )));
+    }
+
+    let story = Story::new(&json["root"])?;
+    let mut walker = Walker {
+        story: &story,
+        warnings: Vec::new(),
+    };
+    let initial_state = walker.declare_globals();
+
+    let start = story.settle(story.enter(0));
+    let mut ids = HashMap::from([(start, 1)]);
+    let mut queue = VecDeque::from([start]);
+    let mut walks = Vec::new();
+    while let Some(pointer) = queue.pop_front() {
+        let walk = walker.walk(pointer);
+        for choice in &walk.choices {
+            if !ids.contains_key(&choice.target) {
+                if ids.len() == MAX_PAGES {
+                    return Err(InkError(format!("story has more than {} pages", MAX_PAGES)));
+                }
+                ids.insert(choice.target, ids.len() as u32 + 1);
+                queue.push_back(choice.target);
+            }
+        }
+        walks.push((pointer, walk));
+    }
+
+    let title = walks[0]
+        .1
+        .tags
+        .iter()
+        .find_map(|tag| tag.strip_prefix("title:"))
+        .map(|title| title.trim().to_string())
+        .unwrap_or_else(|| name.to_string());
+
+    // Assignments run when the page is shown, and choices are where a book
+    // applies effects, so each page's effects go on every choice leading to it.
+    let page_effects: HashMap<Pointer, Vec<Effect>> = walks
+        .iter()
+        .map(|(pointer, walk)| (*pointer, walk.effects.clone()))
+        .collect();
+    if !walks[0].1.effects.is_empty() {
+        walker.warn(
+            start,
+            "variables set before the first choice are ignored".to_string(),
+        );
+    }
+
+    let pages = walks
+        .into_iter()
+        .map(|(pointer, walk)| {
+            let ending = (walk.ended && walk.choices.is_empty()).then(|| Ending {
+                kind: walk
+                    .tags
+                    .iter()
+                    .find_map(|tag| EndingKind::from_tag(tag))
+                    .unwrap_or(EndingKind::Neutral),
+                title: story.knot_name(pointer).unwrap_or(&title).to_string(),
+            });
+            Page {
+                id: ids[&pointer],
+                content: tidy_content(&walk.content),
+                choices: walk
+                    .choices
+                    .into_iter()
+                    .map(|choice| Choice {
+                        text: choice.text,
+                        target_page_id: ids[&choice.target],
+                        conditions: choice.conditions,
+                        effects: page_effects[&choice.target].clone(),
+                        when_unmet: UnmetChoice::Hidden,
+                    })
+                    .collect(),
+                ending,
+            }
+        })
+        .collect();
+
+    Ok(InkImport {
+        book: Book {
+            id: id_from_name(&title),
+            title,
+            summary: String::new(),
+            pages,
+            starting_page: 1,
+            initial_state,
+        },
+        warnings: walker.warnings,
+    })
+}
+
+/// A position in the story: the `index`th element of a container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Pointer {
+    container: usize,
+    index: usize,
+}
+
+enum Node<'a> {
+    Value(&'a Json),
+    Container(usize),
+}
+
+struct Container<'a> {
+    path: String,
+    content: Vec<Node<'a>>,
+    named: HashMap<&'a str, usize>,
+    parent: Option<usize>,
+    /// Where the container sits in its parent's content. Containers that
+    /// are only reachable by name, such as knots, have none.
+    index_in_parent: Option<usize>,
+}
+
+/// The compiled story's container tree.
+struct Story<'a> {
+    containers: Vec<Container<'a>>,
+}
+
+impl<'a> Story<'a> {
+    fn new(root: &'a Json) -> Result<Self, InkError> {
+        let mut story = Story {
+            containers: Vec::new(),
+        };
+        story.add_container(root, String::new(), None, None)?;
+        Ok(story)
+    }
+
+    fn add_container(
+        &mut self,
+        json: &'a Json,
+        path: String,
+        parent: Option<usize>,
+        index_in_parent: Option<usize>,
+    ) -> Result<usize, InkError> {
+        let items = json
+            .as_array()
+            .ok_or_else(|| InkError(format!("container {} is not an array", path)))?;
+        let (terminator, content) = items
+            .split_last()
+            .ok_or_else(|| InkError(format!("container {} is empty", path)))?;
+
+        let id = self.containers.len();
+        self.containers.push(Container {
+            path: path.clone(),
+            content: Vec::new(),
+            named: HashMap::new(),
+            parent,
+            index_in_parent,
+        });
+
+        for (index, item) in content.iter().enumerate() {
+            let node = if item.is_array() {
+                let name = container_name(item);
+                let child_path = join_path(&path, name.unwrap_or(&index.to_string()));
+                let child = self.add_container(item, child_path, Some(id), Some(index))?;
+                if let Some(name) = name {
+                    self.containers[id].named.insert(name, child);
+                }
+                Node::Container(child)
+            } else {
+                Node::Value(item)
+            };
+            self.containers[id].content.push(node);
+        }
+
+        if let Some(named) = terminator.as_object() {
+            for (name, item) in named {
+                if name.starts_with('#') {
+                    continue;
+                }
+                let child = self.add_container(item, join_path(&path, name), Some(id), None)?;
+                self.containers[id].named.insert(name, child);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Finds the position a path points at. Relative paths start with `.`
+    /// and, as in the ink runtime, their first `^` stands for the container
+    /// holding the object the path belongs to.
+    fn resolve(&self, path: &str, from: usize) -> Option<Pointer> {
+        let (mut container, components): (_, Vec<_>) = match path.strip_prefix('.') {
+            Some(relative) => (from, relative.split('.').skip(1).collect()),
+            None => (0, path.split('.').collect()),
+        };
+        for (position, component) in components.iter().enumerate() {
+            if *component == "^" {
+                container = self.containers[container].parent?;
+            } else if let Ok(index) = component.parse::<usize>() {
+                match self.containers[container].content.get(index)? {
+                    Node::Container(child) => container = *child,
+                    Node::Value(_) if position == components.len() - 1 => {
+                        return Some(Pointer { container, index })
+                    }
+                    Node::Value(_) => return None,
+                }
+            } else {
+                container = *self.containers[container].named.get(component)?;
+            }
+        }
+        Some(self.enter(container))
+    }
+
+    /// The start of `container`, stepping into any containers it opens with
+    /// so that the same position always gets the same pointer.
+    fn enter(&self, mut container: usize) -> Pointer {
+        while let Some(Node::Container(child)) = self.containers[container].content.first() {
+            container = *child;
+        }
+        Pointer {
+            container,
+            index: 0,
+        }
+    }
+
+    /// Where reading from `pointer` really starts, skipping line breaks and
+    /// plain diverts, so that `* [Go back] -> start` leads to the same page as
+    /// the start itself.
+    fn settle(&self, mut pointer: Pointer) -> Pointer {
+        let mut seen = HashSet::new();
+        while seen.insert(pointer) {
+            let container = &self.containers[pointer.container];
+            let next = match container.content.get(pointer.index) {
+                None => match (container.parent, container.index_in_parent) {
+                    (Some(parent), Some(index)) => Pointer {
+                        container: parent,
+                        index: index + 1,
+                    },
+                    _ => break,
+                },
+                Some(Node::Container(child)) => self.enter(*child),
+                Some(Node::Value(Json::String(text)))
+                    if text == "\n" || (text.starts_with('^') && text[1..].trim().is_empty()) =>
+                {
+                    Pointer {
+                        index: pointer.index + 1,
+                        ..pointer
+                    }
+                }
+                Some(Node::Value(Json::Object(divert)))
+                    if divert.len() == 1 && divert.contains_key("->") =>
+                {
+                    let target = divert["->"].as_str().unwrap_or_default();
+                    match self.resolve(target, pointer.container) {
+                        Some(target) => target,
+                        None => break,
+                    }
+                }
+                Some(Node::Value(_)) => break,
+            };
+            pointer = next;
+        }
+        pointer
+    }
+
+    /// The knot a position belongs to, used to title endings.
+    fn knot_name(&self, pointer: Pointer) -> Option<&str> {
+        let path = &self.containers[pointer.container].path;
+        let knot = path.split('.').next()?;
+        (!knot.is_empty() && knot.parse::<usize>().is_err()).then_some(knot)
+    }
+
+    fn describe(&self, pointer: Pointer) -> String {
+        let path = &self.containers[pointer.container].path;
+        if path.is_empty() {
+            "story start".to_string()
+        } else {
+            path.clone()
+        }
+    }
+}
+
+fn container_name(json: &Json) -> Option<&str> {
+    json.as_array()?.last()?.get("#n")?.as_str()
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", parent, name)
+    }
+}
+
+/// What a value on the evaluation stack is known to be without running the
+/// story.
+#[derive(Debug, Clone)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    /// A global variable as read, before anything is done with it.
+    Var(String),
+    /// A variable plus a number, as in `~ gold = gold + 5`.
+    Sum(String, i64),
+    Conditions(Vec<Condition>),
+    Target(Pointer),
+    /// Something only the ink runtime can work out.
+    Unknown(&'static str),
+}
+
+impl Value {
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "text",
+            Value::Int(_) => "a number",
+            Value::Bool(_) => "a boolean",
+            Value::Var(_) => "a variable",
+            Value::Sum(..) | Value::Conditions(_) => "an expression",
+            Value::Target(_) => "a divert target",
+            Value::Unknown(what) => what,
+        }
+    }
+
+    /// The conditions for this value to be true, when a book can express
+    /// them.
+    fn conditions(self) -> Option<Vec<Condition>> {
+        match self {
+            Value::Var(flag) => Some(vec![Condition::FlagSet { flag }]),
+            Value::Conditions(conditions) => Some(conditions),
+            Value::Bool(true) => Some(Vec::new()),
+            _ => None,
+        }
+    }
+}
+
+fn negate(condition: Condition) -> Condition {
+    match condition {
+        Condition::FlagSet { flag } => Condition::FlagUnset { flag },
+        Condition::FlagUnset { flag } => Condition::FlagSet { flag },
+        Condition::HasItem { item } => Condition::LacksItem { item },
+        Condition::LacksItem { item } => Condition::HasItem { item },
+        Condition::CounterAtLeast { counter, value } => Condition::CounterBelow { counter, value },
+        Condition::CounterBelow { counter, value } => Condition::CounterAtLeast { counter, value },
+    }
+}
+
+/// Applies one of ink's native functions, such as `+` or `>=`.
+fn apply_native(op: &str, stack: &mut Vec<Value>) -> Option<Value> {
+    let unary = matches!(
+        op,
+        "_" | "!"
+            | "FLOOR"
+            | "CEILING"
+            | "INT"
+            | "FLOAT"
+            | "LIST_COUNT"
+            | "LIST_MIN"
+            | "LIST_MAX"
+            | "LIST_ALL"
+            | "LIST_INVERT"
+            | "LIST_VALUE"
+    );
+    let binary = matches!(
+        op,
+        "+" | "-"
+            | "/"
+            | "*"
+            | "%"
+            | "=="
+            | ">"
+            | "<"
+            | ">="
+            | "<="
+            | "!="
+            | "&&"
+            | "||"
+            | "MIN"
+            | "MAX"
+            | "POW"
+            | "?"
+            | "!?"
+            | "L^"
+    );
+    if unary {
+        let a = stack.pop()?;
+        return Some(match (op, a) {
+            ("!", Value::Bool(b)) => Value::Bool(!b),
+            ("!", Value::Var(flag)) => Value::Conditions(vec![Condition::FlagUnset { flag }]),
+            ("!", Value::Conditions(mut conditions)) if conditions.len() == 1 => {
+                Value::Conditions(vec![negate(conditions.remove(0))])
+            }
+            ("_", Value::Int(n)) => Value::Int(-n),
+            _ => Value::Unknown("an expression"),
+        });
+    }
+    if !binary {
+        return None;
+    }
+    let b = stack.pop()?;
+    let a = stack.pop()?;
+    Some(match (op, a, b) {
+        ("+", Value::Int(a), Value::Int(b)) => Value::Int(a + b),
+        ("-", Value::Int(a), Value::Int(b)) => Value::Int(a - b),
+        ("+", Value::Var(var), Value::Int(by)) => Value::Sum(var, by),
+        ("-", Value::Var(var), Value::Int(by)) => Value::Sum(var, -by),
+        ("+", Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
+        (">=", Value::Var(counter), Value::Int(value)) => {
+            Value::Conditions(vec![Condition::CounterAtLeast { counter, value }])
+        }
+        (">", Value::Var(counter), Value::Int(value)) => {
+            Value::Conditions(vec![Condition::CounterAtLeast {
+                counter,
+                value: value + 1,
+            }])
+        }
+        ("<", Value::Var(counter), Value::Int(value)) => {
+            Value::Conditions(vec![Condition::CounterBelow { counter, value }])
+        }
+        ("<=", Value::Var(counter), Value::Int(value)) => {
+            Value::Conditions(vec![Condition::CounterBelow {
+                counter,
+                value: value + 1,
+            }])
+        }
+        ("==", Value::Var(flag), Value::Bool(true))
+        | ("!=", Value::Var(flag), Value::Bool(false)) => {
+            Value::Conditions(vec![Condition::FlagSet { flag }])
+        }
+        ("==", Value::Var(flag), Value::Bool(false))
+        | ("!=", Value::Var(flag), Value::Bool(true)) => {
+            Value::Conditions(vec![Condition::FlagUnset { flag }])
+        }
+        ("==", Value::Var(counter), Value::Int(value)) => Value::Conditions(vec![
+            Condition::CounterAtLeast {
+                counter: counter.clone(),
+                value,
+            },
+            Condition::CounterBelow {
+                counter,
+                value: value + 1,
+            },
+        ]),
+        ("&&", a, b) => match (a.conditions(), b.conditions()) {
+            (Some(mut a), Some(b)) => {
+                a.extend(b);
+                Value::Conditions(a)
+            }
+            _ => Value::Unknown("an expression"),
+        },
+        _ => Value::Unknown("an expression"),
+    })
+}
+
+struct PendingChoice {
+    text: String,
+    target: Pointer,
+    conditions: Vec<Condition>,
+}
+
+/// What running the story from one position up to its next choices gives.
+#[derive(Default)]
+struct Walk {
+    content: String,
+    choices: Vec<PendingChoice>,
+    effects: Vec<Effect>,
+    tags: Vec<String>,
+    /// Whether the flow reached `-> END` or `-> DONE` rather than stopping
+    /// on something unsupported.
+    ended: bool,
+}
+
+struct Walker<'s, 'a> {
+    story: &'s Story<'a>,
+    warnings: Vec<ImportWarning>,
+}
+
+impl Walker<'_, '_> {
+    fn warn(&mut self, at: Pointer, message: String) {
+        let passage = self.story.describe(at);
+        if !self
+            .warnings
+            .iter()
+            .any(|w| w.passage == passage && w.message == message)
+        {
+            self.warnings.push(ImportWarning {
+                line: None,
+                passage,
+                message,
+            });
+        }
+    }
+
+    /// The playthrough state the `VAR` declarations give: `true` variables
+    /// start as set flags and whole numbers as counters. Other values cannot
+    /// be carried over and are reported.
+    fn declare_globals(&mut self) -> PlaythroughState {
+        let mut state = PlaythroughState::default();
+        let Some(&decl) = self.story.containers[0].named.get("global decl") else {
+            return state;
+        };
+        let start = Pointer {
+            container: decl,
+            index: 0,
+        };
+        let mut stack = Vec::new();
+        for node in &self.story.containers[decl].content {
+            let Node::Value(json) = node else { continue };
+            if let Some(var) = json.get("VAR=").and_then(Json::as_str) {
+                match stack.pop() {
+                    Some(Value::Bool(false)) | Some(Value::Int(0)) => {}
+                    Some(Value::Bool(true)) => {
+                        state.flags.insert(var.to_string());
+                    }
+                    Some(Value::Int(n)) => {
+                        state.counters.insert(var.to_string(), n);
+                    }
+                    _ => self.warn(
+                        start,
+                        format!("{} starts unset instead of its declared value", var),
+                    ),
+                }
+            } else if let Some(n) = json.as_i64() {
+                stack.push(Value::Int(n));
+            } else if let Some(b) = json.as_bool() {
+                stack.push(Value::Bool(b));
+            } else if !json.is_string() {
+                stack.push(Value::Unknown("a value"));
+            }
+        }
+        state
+    }
+
+    fn walk(&mut self, start: Pointer) -> Walk {
+        let mut walk = Walk::default();
+        let mut pointer = start;
+        let mut stack: Vec<Value> = Vec::new();
+        let mut temps: HashMap<String, Value> = HashMap::new();
+        let mut strings: Vec<String> = Vec::new();
+        let mut tag: Option<String> = None;
+        let mut glue = false;
+        let mut diverted_to = HashSet::from([start]);
+
+        for _ in 0..MAX_STEPS_PER_PAGE {
+            let container = &self.story.containers[pointer.container];
+            let Some(node) = container.content.get(pointer.index) else {
+                // Step out of a finished container into its parent; the
+                // flow stops at the end of a knot or of the story.
+                match (container.parent, container.index_in_parent) {
+                    (Some(parent), Some(index)) => {
+                        pointer = Pointer {
+                            container: parent,
+                            index: index + 1,
+                        };
+                        continue;
+                    }
+                    _ => {
+                        walk.ended = true;
+                        return walk;
+                    }
+                }
+            };
+            let json = match node {
+                Node::Container(child) => {
+                    pointer = Pointer {
+                        container: *child,
+                        index: 0,
+                    };
+                    continue;
+                }
+                Node::Value(json) => *json,
+            };
+            let here = pointer;
+            pointer.index += 1;
+
+            let output = |walk: &mut Walk,
+                          strings: &mut Vec<String>,
+                          tag: &mut Option<String>,
+                          glue: &mut bool,
+                          text: &str| {
+                if let Some(tag) = tag {
+                    tag.push_str(text);
+                } else if let Some(string) = strings.last_mut() {
+                    string.push_str(text);
+                } else if text == "\n" {
+                    if !*glue {
+                        walk.content.push('\n');
+                    }
+                } else {
+                    walk.content.push_str(text);
+                    *glue = false;
+                }
+            };
+
+            match json {
+                Json::String(command) => match command.as_str() {
+                    text if text.starts_with('^') => {
+                        output(&mut walk, &mut strings, &mut tag, &mut glue, &text[1..])
+                    }
+                    "\n" => output(&mut walk, &mut strings, &mut tag, &mut glue, "\n"),
+                    "<>" => {
+                        while walk.content.ends_with(char::is_whitespace) {
+                            walk.content.pop();
+                        }
+                        walk.content.push(' ');
+                        glue = true;
+                    }
+                    "ev" | "/ev" | "nop" | "void" => {}
+                    "str" => strings.push(String::new()),
+                    "/str" => {
+                        let string = strings.pop().unwrap_or_default();
+                        stack.push(Value::Str(string));
+                    }
+                    "#" => tag = Some(String::new()),
+                    "/#" => {
+                        if let Some(tag) = tag.take() {
+                            walk.tags.push(tag.trim().to_string());
+                        }
+                    }
+                    "out" => match stack.pop() {
+                        Some(Value::Str(text)) => {
+                            output(&mut walk, &mut strings, &mut tag, &mut glue, &text)
+                        }
+                        Some(Value::Int(n)) => {
+                            output(&mut walk, &mut strings, &mut tag, &mut glue, &n.to_string())
+                        }
+                        Some(Value::Var(var)) => {
+                            self.warn(here, format!("printing {} is not supported", var))
+                        }
+                        value => self.warn(
+                            here,
+                            format!(
+                                "printing {} is not supported",
+                                value.map_or("nothing", |value| value.describe())
+                            ),
+                        ),
+                    },
+                    "pop" => {
+                        stack.pop();
+                    }
+                    "du" => {
+                        if let Some(top) = stack.last().cloned() {
+                            stack.push(top);
+                        }
+                    }
+                    "done" | "end" => {
+                        walk.ended = true;
+                        return walk;
+                    }
+                    "->->" | "~ret" => {
+                        self.warn(here, "tunnels and functions are not supported".to_string());
+                        return walk;
+                    }
+                    "thread" => {
+                        self.warn(here, "threads are not supported".to_string());
+                    }
+                    "visit" | "turn" | "turns" | "choiceCnt" => {
+                        stack.push(Value::Unknown("a visit or turn count"))
+                    }
+                    "readc" | "srnd" | "lrnd" => {
+                        stack.pop();
+                        stack.push(Value::Unknown("a visit count or random number"));
+                    }
+                    "seq" | "rnd" | "listInt" => {
+                        stack.pop();
+                        stack.pop();
+                        stack.push(Value::Unknown("a sequence or random number"));
+                    }
+                    "range" => {
+                        stack.pop();
+                        stack.pop();
+                        stack.pop();
+                        stack.push(Value::Unknown("a list"));
+                    }
+                    op => {
+                        if apply_native(op, &mut stack)
+                            .map(|value| stack.push(value))
+                            .is_none()
+                        {
+                            self.warn(here, format!("unknown instruction \"{}\"", op));
+                        }
+                    }
+                },
+                Json::Number(n) => stack.push(match n.as_i64() {
+                    Some(n) => Value::Int(n),
+                    None => Value::Unknown("a decimal number"),
+                }),
+                Json::Bool(b) => stack.push(Value::Bool(*b)),
+                Json::Object(object) => {
+                    if let Some(target) = object.get("->").and_then(Json::as_str) {
+                        let is_variable = object.get("var").is_some_and(|v| v == true);
+                        let is_conditional = object.get("c").is_some_and(|v| v == true);
+                        if is_conditional {
+                            stack.pop();
+                            self.warn(
+                                here,
+                                "conditional text and sequences are not supported, \
+                                 only their default branch is kept"
+                                    .to_string(),
+                            );
+                            continue;
+                        }
+                        let destination = if is_variable {
+                            match temps.get(target) {
+                                Some(Value::Target(pointer)) => Some(*pointer),
+                                _ => None,
+                            }
+                        } else {
+                            self.story.resolve(target, here.container)
+                        };
+                        let Some(destination) = destination else {
+                            self.warn(here, format!("cannot follow divert to {}", target));
+                            return walk;
+                        };
+                        if !diverted_to.insert(destination) {
+                            self.warn(here, "loops without a choice are not supported".to_string());
+                            return walk;
+                        }
+                        pointer = destination;
+                    } else if object.contains_key("f()") || object.contains_key("x()") {
+                        self.warn(here, "functions are not supported".to_string());
+                        stack.push(Value::Unknown("a function result"));
+                    } else if object.contains_key("->t->") {
+                        self.warn(here, "tunnels are not supported".to_string());
+                    } else if let Some(target) = object.get("*").and_then(Json::as_str) {
+                        let flags = object.get("flg").and_then(Json::as_u64).unwrap_or(0);
+                        self.choice(here, target, flags, &mut stack, &mut walk);
+                    } else if let Some(var) = object.get("VAR=").and_then(Json::as_str) {
+                        let value = stack.pop();
+                        self.assign(here, var, value, &mut walk);
+                    } else if let Some(var) = object.get("temp=").and_then(Json::as_str) {
+                        let value = stack.pop().unwrap_or(Value::Unknown("a value"));
+                        temps.insert(var.to_string(), value);
+                    } else if let Some(var) = object.get("VAR?").and_then(Json::as_str) {
+                        stack.push(
+                            temps
+                                .get(var)
+                                .cloned()
+                                .unwrap_or_else(|| Value::Var(var.to_string())),
+                        );
+                    } else if let Some(target) = object.get("^->").and_then(Json::as_str) {
+                        stack.push(match self.story.resolve(target, here.container) {
+                            Some(pointer) => Value::Target(pointer),
+                            None => Value::Unknown("a divert target"),
+                        });
+                    } else if let Some(text) = object.get("#").and_then(Json::as_str) {
+                        walk.tags.push(text.trim().to_string());
+                    } else if object.contains_key("CNT?") {
+                        stack.push(Value::Unknown("a visit count"));
+                    } else if object.contains_key("list") {
+                        self.warn(here, "lists are not supported".to_string());
+                        stack.push(Value::Unknown("a list"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.warn(start, "flow runs too long without a choice".to_string());
+        walk
+    }
+
+    fn choice(
+        &mut self,
+        here: Pointer,
+        target: &str,
+        flags: u64,
+        stack: &mut Vec<Value>,
+        walk: &mut Walk,
+    ) {
+        let condition = if flags & HAS_CONDITION != 0 {
+            stack.pop()
+        } else {
+            None
+        };
+        let mut text = String::new();
+        if flags & HAS_CHOICE_ONLY_CONTENT != 0 {
+            if let Some(Value::Str(choice_only)) = stack.pop() {
+                text = choice_only;
+            }
+        }
+        if flags & HAS_START_CONTENT != 0 {
+            if let Some(Value::Str(start)) = stack.pop() {
+                text = start + &text;
+            }
+        }
+
+        if flags & IS_INVISIBLE_DEFAULT != 0 {
+            self.warn(here, "fallback choices are not supported".to_string());
+            return;
+        }
+        let Some(target) = self.story.resolve(target, here.container) else {
+            self.warn(here, format!("cannot follow choice to {}", target));
+            return;
+        };
+        let target = self.story.settle(target);
+        let conditions = match condition {
+            None => Vec::new(),
+            Some(condition) => {
+                let what = condition.describe();
+                condition.conditions().unwrap_or_else(|| {
+                    self.warn(
+                        here,
+                        format!(
+                            "the condition on \"{}\" depends on {}, so the choice is always shown",
+                            text.trim(),
+                            what
+                        ),
+                    );
+                    Vec::new()
+                })
+            }
+        };
+        walk.choices.push(PendingChoice {
+            text: text.trim().to_string(),
+            target,
+            conditions,
+        });
+    }
+
+    fn assign(&mut self, here: Pointer, var: &str, value: Option<Value>, walk: &mut Walk) {
+        let effect = match value {
+            Some(Value::Bool(true)) => Effect::SetFlag {
+                flag: var.to_string(),
+            },
+            Some(Value::Bool(false)) => Effect::ClearFlag {
+                flag: var.to_string(),
+            },
+            Some(Value::Sum(counter, by)) if counter == var => Effect::Increment {
+                counter: counter.clone(),
+                by,
+            },
+            value => {
+                self.warn(
+                    here,
+                    format!(
+                        "{} is set to {}, but only true, false and adding to or \
+                         subtracting from it are supported",
+                        var,
+                        value.as_ref().map_or("nothing", Value::describe)
+                    ),
+                );
+                return;
+            }
+        };
+        walk.effects.push(effect);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Compiled from `fixtures/lighthouse.ink`.
+    const LIGHTHOUSE: &str = include_str!("fixtures/lighthouse.ink.json");
+
+    fn choices(page: &Page) -> Vec<(&str, u32)> {
+        page.choices
+            .iter()
+            .map(|choice| (choice.text.as_str(), choice.target_page_id))
+            .collect()
+    }
+
+    fn as_json<T: serde::Serialize>(value: &T) -> Json {
+        serde_json::to_value(value).unwrap()
+    }
+
+    /// A story of `pages` knots, each with one choice leading to the next.
+    fn chain(pages: usize) -> String {
+        let mut knots = serde_json::Map::new();
+        for i in 0..pages {
+            let next = if i + 1 == pages {
+                json!(["^The end.", "\n", "end", null])
+            } else {
+                json!([
+                    format!("^Room {}.", i),
+                    "\n",
+                    "ev", "str", "^On", "/str", "/ev",
+                    { "*": ".^.c-0", "flg": 20 },
+                    { "c-0": ["\n", { "->": format!("k{}", i + 1) }, { "#f": 5 }] }
+                ])
+            };
+            knots.insert(format!("k{}", i), next);
+        }
+        json!({
+            "inkVersion": 21,
+            "root": [[{ "->": "k0" }, null], "done", knots],
+            "listDefs": {}
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn turns_each_set_of_choices_into_a_page() {
+        let import = parse_ink_json(LIGHTHOUSE, "lighthouse").unwrap();
+        let book = &import.book;
+        assert_eq!(book.title, "The Lighthouse");
+        assert_eq!(book.id, id_from_name("The Lighthouse"));
+        assert_eq!(book.starting_page, 1);
+        assert_eq!(book.pages.len(), 5);
+
+        let start = &book.pages[0];
+        assert_eq!(start.content, "You reach the lighthouse at dusk.");
+        assert_eq!(
+            choices(start),
+            [("Climb the stairs", 2), ("Search the shed", 3)]
+        );
+        let stairs = &book.pages[1];
+        assert_eq!(stairs.content, "The stairs vanish into darkness.");
+        assert_eq!(
+            choices(stairs),
+            [
+                ("Light the way", 4),
+                ("Burn the oil", 4),
+                ("Strike a match", 4),
+                ("Feel your way up", 5)
+            ]
+        );
+        let shed = &book.pages[2];
+        assert_eq!(shed.content, "You find a lamp and some oil.");
+        assert_eq!(choices(shed), [("Go back", 2)]);
+    }
+
+    #[test]
+    fn carries_variables_over_as_conditions_and_effects() {
+        let book = parse_ink_json(LIGHTHOUSE, "lighthouse").unwrap().book;
+
+        // The shed's assignments apply when the reader goes there.
+        let search = &book.pages[0].choices[1];
+        assert_eq!(
+            as_json(&search.effects),
+            json!([
+                { "type": "set_flag", "flag": "lamp" },
+                { "type": "increment", "counter": "oil", "by": 2 }
+            ])
+        );
+        assert!(book.pages[0].choices[0].effects.is_empty());
+
+        let stairs = &book.pages[1].choices;
+        assert_eq!(
+            as_json(&stairs[0].conditions),
+            json!([{ "type": "flag_set", "flag": "lamp" }])
+        );
+        assert_eq!(
+            as_json(&stairs[1].conditions),
+            json!([{ "type": "counter_at_least", "counter": "oil", "value": 2 }])
+        );
+        assert_eq!(
+            as_json(&stairs[2].conditions),
+            json!([{ "type": "counter_at_least", "counter": "matches", "value": 1 }])
+        );
+        assert_eq!(
+            as_json(&stairs[3].conditions),
+            json!([{ "type": "flag_set", "flag": "brave" }])
+        );
+    }
+
+    #[test]
+    fn declared_values_become_the_initial_state() {
+        let import = parse_ink_json(LIGHTHOUSE, "lighthouse").unwrap();
+        let state = &import.book.initial_state;
+        assert_eq!(state.flags, BTreeSet::from(["brave".to_string()]));
+        assert_eq!(state.counters, BTreeMap::from([("matches".to_string(), 3)]));
+        assert!(state.inventory.is_empty());
+
+        // Everything the reader can do on the stairs is open to them
+        // straight away, except the lamp they have not found yet.
+        let stairs = &import.book.pages[1].choices;
+        let available: Vec<_> = stairs.iter().map(|c| state.is_available(c)).collect();
+        assert_eq!(available, [false, false, true, true]);
+
+        let messages: Vec<_> = import
+            .warnings
+            .iter()
+            .map(|warning| warning.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            ["keeper starts unset instead of its declared value"]
+        );
+    }
+
+    #[test]
+    fn pages_ending_the_story_are_endings() {
+        let book = parse_ink_json(LIGHTHOUSE, "lighthouse").unwrap().book;
+        let top = book.pages[3].ending.as_ref().unwrap();
+        assert_eq!((top.kind, top.title.as_str()), (EndingKind::Victory, "top"));
+        assert_eq!(book.pages[3].content, "The light sweeps the sea.");
+        let fall = book.pages[4].ending.as_ref().unwrap();
+        assert_eq!(
+            (fall.kind, fall.title.as_str()),
+            (EndingKind::Death, "fall")
+        );
+        assert!(book.pages[..3].iter().all(|page| page.ending.is_none()));
+    }
+
+    #[test]
+    fn refuses_stories_with_too_many_pages() {
+        let book = parse_ink_json(&chain(MAX_PAGES), "chain").unwrap().book;
+        assert_eq!(book.pages.len(), MAX_PAGES);
+        assert!(book.pages[MAX_PAGES - 1].ending.is_some());
+
+        let error = parse_ink_json(&chain(MAX_PAGES + 1), "chain").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("story has more than {} pages", MAX_PAGES)
+        );
+    }
+
+    #[test]
+    fn refuses_unsupported_versions() {
+        let old = LIGHTHOUSE.replace(r#""inkVersion":21"#, r#""inkVersion":18"#);
+        assert!(parse_ink_json(&old, "lighthouse")
+            .unwrap_err()
+            .to_string()
+            .contains("ink version 18 is not supported"));
+        assert!(parse_ink_json("{\"root\": []}", "lighthouse").is_err());
+    }
+}
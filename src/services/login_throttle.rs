@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// Failed login attempts against one username or from one address.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Attempts {
+    pub failures: u32,
+    pub last_failure: u64,
+}
+
+/// Where failed login attempts are counted. The in-memory store is enough
+/// for a single server; several servers would need to share one.
+pub trait AttemptStore: Send + Sync {
+    /// In one step, checks every key against its policy and, unless any of
+    /// them has to wait, counts a failure for each, after forgetting
+    /// failures older than the policy keeps. Returns the new counts, or the
+    /// longest wait without counting anything.
+    fn reserve(&self, keys: &[(String, ThrottlePolicy)], now: u64) -> Result<Vec<Attempts>, u64>;
+    /// Takes back one failure counted for `key`.
+    fn release(&self, key: &str);
+    fn clear(&self, key: &str);
+}
+
+#[derive(Default)]
+pub struct InMemoryAttemptStore {
+    attempts: RwLock<HashMap<String, Attempts>>,
+}
+
+impl InMemoryAttemptStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AttemptStore for InMemoryAttemptStore {
+    fn reserve(&self, keys: &[(String, ThrottlePolicy)], now: u64) -> Result<Vec<Attempts>, u64> {
+        let mut attempts = self.attempts.write().unwrap();
+        let wait = keys
+            .iter()
+            .filter_map(|(key, policy)| policy.wait_secs(*attempts.get(key)?, now))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+
+        Ok(keys
+            .iter()
+            .map(|(key, policy)| {
+                let entry = attempts.entry(key.clone()).or_default();
+                if now >= entry.last_failure + policy.forget_after_secs {
+                    entry.failures = 0;
+                }
+                entry.failures += 1;
+                entry.last_failure = now;
+                *entry
+            })
+            .collect())
+    }
+
+    fn release(&self, key: &str) {
+        let mut attempts = self.attempts.write().unwrap();
+        if let Some(entry) = attempts.get_mut(key) {
+            entry.failures = entry.failures.saturating_sub(1);
+            if entry.failures == 0 {
+                attempts.remove(key);
+            }
+        }
+    }
+
+    fn clear(&self, key: &str) {
+        self.attempts.write().unwrap().remove(key);
+    }
+}
+
+/// How quickly repeated failures slow down further attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    /// Failures allowed before further attempts have to wait.
+    pub free_failures: u32,
+    /// Wait after the first failure past `free_failures`, doubled for each
+    /// failure after that up to `max_delay_secs`.
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    /// Failures after which attempts are refused for `lockout_secs`.
+    pub lockout_failures: u32,
+    pub lockout_secs: u64,
+    /// Failures are forgotten this long after the last one.
+    pub forget_after_secs: u64,
+}
+
+impl ThrottlePolicy {
+    pub const PER_USERNAME: ThrottlePolicy = ThrottlePolicy {
+        free_failures: 3,
+        base_delay_secs: 2,
+        max_delay_secs: 60,
+        lockout_failures: 10,
+        lockout_secs: 15 * 60,
+        forget_after_secs: 60 * 60,
+    };
+
+    pub const PER_IP: ThrottlePolicy = ThrottlePolicy {
+        free_failures: 10,
+        base_delay_secs: 1,
+        max_delay_secs: 60,
+        lockout_failures: 50,
+        lockout_secs: 60 * 60,
+        forget_after_secs: 60 * 60,
+    };
+
+    /// Seconds left before another attempt is allowed after `attempts`.
+    pub fn wait_secs(&self, attempts: Attempts, now: u64) -> Option<u64> {
+        if now >= attempts.last_failure + self.forget_after_secs {
+            return None;
+        }
+        let wait = if attempts.failures >= self.lockout_failures {
+            self.lockout_secs
+        } else if attempts.failures > self.free_failures {
+            let doublings = (attempts.failures - self.free_failures - 1).min(32);
+            self.base_delay_secs
+                .saturating_mul(1 << doublings)
+                .min(self.max_delay_secs)
+        } else {
+            return None;
+        };
+        let until = attempts.last_failure + wait;
+        (until > now).then(|| until - now)
+    }
+}
+
+/// Slows down password guessing by counting failed logins per username and
+/// per client address, with exponential backoff and then a temporary
+/// lockout. Times are passed in as seconds so callers control the clock.
+pub struct LoginThrottle {
+    store: Arc<dyn AttemptStore>,
+    per_username: ThrottlePolicy,
+    per_ip: ThrottlePolicy,
+}
+
+impl LoginThrottle {
+    pub fn new(
+        store: Arc<dyn AttemptStore>,
+        per_username: ThrottlePolicy,
+        per_ip: ThrottlePolicy,
+    ) -> Self {
+        Self {
+            store,
+            per_username,
+            per_ip,
+        }
+    }
+
+    pub fn in_memory() -> Self {
+        Self::new(
+            Arc::new(InMemoryAttemptStore::new()),
+            ThrottlePolicy::PER_USERNAME,
+            ThrottlePolicy::PER_IP,
+        )
+    }
+
+    /// Lets `username` try to sign in from `ip` unless they have to wait,
+    /// in which case the seconds to wait are returned. The attempt is
+    /// counted as a failure straight away, in the same step as the check,
+    /// so a burst of guesses sent at once cannot all get past the check
+    /// before the first has failed. Attempts that turn out not to be wrong
+    /// guesses are taken back with `record_success` or `release`.
+    pub fn check_and_reserve(&self, username: &str, ip: IpAddr, now: u64) -> Result<(), u64> {
+        let keys = self.keys(username, ip);
+        let attempts = self.store.reserve(&keys, now)?;
+        for ((key, policy), attempts) in keys.iter().zip(attempts) {
+            if attempts.failures == policy.lockout_failures {
+                log::warn!(
+                    "Locking out {} for {}s after {} failed logins",
+                    key,
+                    policy.lockout_secs,
+                    attempts.failures
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the username's failures and takes back the address's
+    /// reserved attempt. The address keeps its earlier failures, so signing
+    /// in to one account does not buy more guesses at others.
+    pub fn record_success(&self, username: &str, ip: IpAddr) {
+        self.store.clear(&username_key(username));
+        self.store.release(&ip_key(ip));
+    }
+
+    /// Takes back a reserved attempt that was not a wrong guess, such as a
+    /// right password for a locked account.
+    pub fn release(&self, username: &str, ip: IpAddr) {
+        for (key, _) in self.keys(username, ip) {
+            self.store.release(&key);
+        }
+    }
+
+    fn keys(&self, username: &str, ip: IpAddr) -> [(String, ThrottlePolicy); 2] {
+        [
+            (username_key(username), self.per_username),
+            (ip_key(ip), self.per_ip),
+        ]
+    }
+}
+
+fn username_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+/// IPv6 clients usually get a whole /64, so they are counted per prefix.
+fn ip_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => format!("ip:{}", ip),
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("ip:{:x}:{:x}:{:x}:{:x}::/64", s[0], s[1], s[2], s[3])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const POLICY: ThrottlePolicy = ThrottlePolicy {
+        free_failures: 2,
+        base_delay_secs: 10,
+        max_delay_secs: 40,
+        lockout_failures: 6,
+        lockout_secs: 1000,
+        forget_after_secs: 5000,
+    };
+
+    const ALICE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const BOB: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+    fn throttle(per_username: ThrottlePolicy, per_ip: ThrottlePolicy) -> LoginThrottle {
+        LoginThrottle::new(Arc::new(InMemoryAttemptStore::new()), per_username, per_ip)
+    }
+
+    /// For whichever of usernames and addresses a test is not about.
+    const LENIENT: ThrottlePolicy = ThrottlePolicy {
+        free_failures: 1000,
+        lockout_failures: 1000,
+        ..POLICY
+    };
+
+    #[test]
+    fn backoff_doubles_after_the_free_failures() {
+        let throttle = throttle(POLICY, LENIENT);
+        let mut now = 100;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Err(10));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now + 4), Err(6));
+
+        now += 10;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Err(20));
+        now += 20;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Err(40));
+        now += 40;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+    }
+
+    #[test]
+    fn lockout_after_too_many_failures_then_expiry() {
+        let throttle = throttle(POLICY, LENIENT);
+        let mut now = 100;
+        for _ in 0..POLICY.lockout_failures {
+            while let Err(wait) = throttle.check_and_reserve("alice", ALICE, now) {
+                now += wait;
+            }
+        }
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Err(1000));
+        assert_eq!(
+            throttle.check_and_reserve("alice", ALICE, now + 999),
+            Err(1)
+        );
+
+        // Once the lockout is over the count stays, so the next wrong guess
+        // locks the account again straight away.
+        now += 1000;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Err(1000));
+
+        // Failures are forgotten long after the last one.
+        now += POLICY.forget_after_secs;
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, now), Ok(()));
+    }
+
+    #[test]
+    fn usernames_are_throttled_from_any_address() {
+        let throttle = throttle(POLICY, LENIENT);
+        for ip in [ALICE, BOB, ALICE] {
+            assert_eq!(throttle.check_and_reserve("alice", ip, 100), Ok(()));
+        }
+        assert_eq!(throttle.check_and_reserve("alice", BOB, 100), Err(10));
+        assert_eq!(throttle.check_and_reserve("bob", BOB, 100), Ok(()));
+    }
+
+    #[test]
+    fn addresses_are_throttled_across_usernames() {
+        let throttle = throttle(LENIENT, POLICY);
+        for username in ["alice", "bob", "carol"] {
+            assert_eq!(throttle.check_and_reserve(username, ALICE, 100), Ok(()));
+        }
+        assert_eq!(throttle.check_and_reserve("dave", ALICE, 100), Err(10));
+        assert_eq!(throttle.check_and_reserve("dave", BOB, 100), Ok(()));
+    }
+
+    #[test]
+    fn ipv6_addresses_are_counted_per_64_prefix() {
+        let throttle = throttle(LENIENT, POLICY);
+        for host in 1..=3 {
+            let ip = IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, host));
+            assert_eq!(throttle.check_and_reserve("alice", ip, 100), Ok(()));
+        }
+        let ip = IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 9, 9, 9, 9));
+        assert_eq!(throttle.check_and_reserve("alice", ip, 100), Err(10));
+    }
+
+    #[test]
+    fn success_forgets_the_username_but_not_the_address() {
+        let throttle = throttle(POLICY, POLICY);
+        for _ in 0..2 {
+            throttle.check_and_reserve("alice", ALICE, 100).unwrap();
+        }
+        throttle.check_and_reserve("alice", ALICE, 100).unwrap();
+        throttle.record_success("alice", ALICE);
+
+        // Two failures left on the address, none on the username.
+        assert_eq!(throttle.check_and_reserve("alice", BOB, 100), Ok(()));
+        assert_eq!(throttle.check_and_reserve("bob", ALICE, 100), Ok(()));
+        assert_eq!(throttle.check_and_reserve("carol", ALICE, 100), Err(10));
+    }
+
+    #[test]
+    fn released_attempts_are_not_counted() {
+        let throttle = throttle(POLICY, POLICY);
+        for _ in 0..10 {
+            throttle.check_and_reserve("alice", ALICE, 100).unwrap();
+            throttle.release("alice", ALICE);
+        }
+        assert_eq!(throttle.check_and_reserve("alice", ALICE, 100), Ok(()));
+    }
+
+    #[test]
+    fn parallel_attempts_cannot_skip_the_backoff() {
+        let throttle = Arc::new(throttle(POLICY, LENIENT));
+        let barrier = Arc::new(Barrier::new(20));
+        let allowed: usize = (0..20)
+            .map(|_| {
+                let throttle = throttle.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    throttle.check_and_reserve("alice", ALICE, 100).is_ok()
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|attempt| attempt.join().unwrap() as usize)
+            .sum();
+        assert_eq!(allowed, POLICY.free_failures as usize + 1);
+    }
+}
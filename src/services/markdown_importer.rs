@@ -0,0 +1,429 @@
+use crate::models::book::{Book, Choice, Ending, EndingKind, Page, UnmetChoice};
+use crate::models::progress::PlaythroughState;
+use crate::services::book_loader::id_from_name;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug)]
+pub struct MarkdownError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl MarkdownError {
+    fn new(line: Option<usize>, message: impl Into<String>) -> Self {
+        MarkdownError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for MarkdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The YAML front matter between the opening `---` lines.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FrontMatter {
+    title: String,
+    #[serde(default)]
+    summary: String,
+    /// The anchor of the starting page; the first page when left out.
+    start: Option<String>,
+    id: Option<u32>,
+}
+
+struct Section {
+    anchor: String,
+    heading: String,
+    classes: Vec<String>,
+    line: usize,
+    content: Vec<String>,
+    links: Vec<(String, String, usize)>,
+}
+
+/// Reads a story written in Markdown:
+///
+/// ```markdown
+/// ---
+/// title: The Lighthouse Keeper
+/// summary: A storm, a dark lamp and a ship on the rocks.
+/// start: shore
+/// ---
+///
+/// # On the shore {#shore}
+///
+/// The storm has passed. The lamp is *dark*.
+///
+/// - [Climb the stairs](#lamp-room)
+/// - [Row out to sea](#the-sea)
+///
+/// # Lamp room
+/// ...
+///
+/// # The sea {.death}
+/// ```
+///
+/// Every level one heading starts a page, anchored by its `{#id}` or else by
+/// its text in lower case with dashes for spaces. List items holding nothing
+/// but a link to a page are the page's choices; everything else is the page's
+/// Markdown content. A `{.victory}`, `{.death}`, `{.cliffhanger}` or
+/// `{.ending}` class makes the page an ending titled after its heading.
+pub fn parse_markdown(source: &str) -> Result<Book, MarkdownError> {
+    let lines: Vec<&str> = source.lines().collect();
+    let (front_matter, body_start) = front_matter(&lines)?;
+
+    let mut sections: Vec<Section> = Vec::new();
+    let mut in_code = false;
+    for (index, line) in lines.iter().enumerate().skip(body_start) {
+        let number = index + 1;
+        if line.trim_start().starts_with("```") || line.trim_start().starts_with("~~~") {
+            in_code = !in_code;
+        }
+        if !in_code {
+            if let Some(heading) = line.strip_prefix("# ") {
+                let (heading, anchor, classes) = parse_heading(heading);
+                sections.push(Section {
+                    anchor,
+                    heading,
+                    classes,
+                    line: number,
+                    content: Vec::new(),
+                    links: Vec::new(),
+                });
+                continue;
+            }
+        }
+
+        let Some(section) = sections.last_mut() else {
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Err(MarkdownError::new(
+                Some(number),
+                "text before the first page heading",
+            ));
+        };
+        match (!in_code).then(|| choice_link(line)).flatten() {
+            Some((text, anchor)) => section.links.push((text, anchor, number)),
+            None => section.content.push(line.to_string()),
+        }
+    }
+
+    if sections.is_empty() {
+        return Err(MarkdownError::new(None, "story has no pages"));
+    }
+
+    let mut ids = HashMap::new();
+    for (index, section) in sections.iter().enumerate() {
+        if ids
+            .insert(section.anchor.as_str(), index as u32 + 1)
+            .is_some()
+        {
+            return Err(MarkdownError::new(
+                Some(section.line),
+                format!("page #{} is defined more than once", section.anchor),
+            ));
+        }
+    }
+
+    let starting_page = match &front_matter.start {
+        Some(start) => *ids.get(start.trim_start_matches('#')).ok_or_else(|| {
+            MarkdownError::new(None, format!("start page #{} does not exist", start))
+        })?,
+        None => 1,
+    };
+
+    let pages = sections
+        .iter()
+        .map(|section| {
+            let choices = section
+                .links
+                .iter()
+                .map(|(text, anchor, line)| {
+                    let target_page_id = *ids.get(anchor.as_str()).ok_or_else(|| {
+                        MarkdownError::new(
+                            Some(*line),
+                            format!("choice \"{}\" links to missing page #{}", text, anchor),
+                        )
+                    })?;
+                    Ok(Choice {
+                        text: text.clone(),
+                        target_page_id,
+                        conditions: Vec::new(),
+                        effects: Vec::new(),
+                        when_unmet: UnmetChoice::default(),
+                    })
+                })
+                .collect::<Result<_, MarkdownError>>()?;
+            Ok(Page {
+                id: ids[section.anchor.as_str()],
+                content: section.content.join("\n").trim().to_string(),
+                choices,
+                ending: ending(section),
+            })
+        })
+        .collect::<Result<_, MarkdownError>>()?;
+
+    Ok(Book {
+        id: front_matter
+            .id
+            .unwrap_or_else(|| id_from_name(&front_matter.title)),
+        title: front_matter.title,
+        summary: front_matter.summary,
+        pages,
+        starting_page,
+        initial_state: PlaythroughState::default(),
+    })
+}
+
+fn front_matter(lines: &[&str]) -> Result<(FrontMatter, usize), MarkdownError> {
+    let opening = lines
+        .iter()
+        .position(|line| !line.trim().is_empty())
+        .filter(|&index| lines[index].trim_end() == "---")
+        .ok_or_else(|| MarkdownError::new(Some(1), "missing --- front matter"))?;
+    let closing = lines[opening + 1..]
+        .iter()
+        .position(|line| line.trim_end() == "---")
+        .map(|index| opening + 1 + index)
+        .ok_or_else(|| MarkdownError::new(Some(opening + 1), "front matter is not closed"))?;
+
+    let yaml = lines[opening + 1..closing].join("\n");
+    let front_matter = serde_yaml::from_str(&yaml).map_err(|e| {
+        MarkdownError::new(
+            Some(opening + 1 + e.location().map_or(1, |location| location.line())),
+            e.to_string(),
+        )
+    })?;
+    Ok((front_matter, closing + 1))
+}
+
+/// The text, anchor and classes of a heading such as
+/// `The sea {#sea .death}`.
+fn parse_heading(heading: &str) -> (String, String, Vec<String>) {
+    let heading = heading.trim();
+    let (text, attributes) = match heading
+        .strip_suffix('}')
+        .and_then(|rest| rest.rsplit_once('{'))
+    {
+        Some((text, attributes)) => (text.trim(), attributes),
+        None => (heading, ""),
+    };
+
+    let mut anchor = None;
+    let mut classes = Vec::new();
+    for attribute in attributes.split_whitespace() {
+        if let Some(id) = attribute.strip_prefix('#') {
+            anchor = Some(id.to_string());
+        } else if let Some(class) = attribute.strip_prefix('.') {
+            classes.push(class.to_string());
+        }
+    }
+    let anchor = anchor.unwrap_or_else(|| slug(text));
+    (text.to_string(), anchor, classes)
+}
+
+/// A heading's anchor as GitHub writes it: lower case, with spaces turned
+/// into dashes and other punctuation dropped.
+fn slug(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            ' ' | '-' => Some('-'),
+            c if c.is_alphanumeric() || c == '_' => Some(c.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The text and target of a list item that is only a link to a page, as in
+/// `- [Climb the stairs](#lamp-room)`.
+fn choice_link(line: &str) -> Option<(String, String)> {
+    let item = line.trim();
+    let item = item
+        .strip_prefix("- ")
+        .or_else(|| item.strip_prefix("* "))
+        .or_else(|| item.strip_prefix("+ "))?
+        .trim();
+    let (text, target) = item
+        .strip_prefix('[')?
+        .strip_suffix(')')?
+        .split_once("](#")?;
+    if text.contains(']') || target.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((text.trim().to_string(), target.to_string()))
+}
+
+fn ending(section: &Section) -> Option<Ending> {
+    let kind = section
+        .classes
+        .iter()
+        .find_map(|class| EndingKind::from_tag(class))?;
+    Some(Ending {
+        kind,
+        title: section.heading.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORY: &str = "\
+---
+title: The Lighthouse Keeper
+summary: A storm and a dark lamp.
+start: shore
+---
+
+# Lamp room
+
+The wick is *dry*.
+
+- [Go back down](#shore)
+
+# On the shore {#shore}
+
+The storm has passed.
+
+- [Climb the stairs](#lamp-room)
+- [Row out to sea](#the-sea)
+- [Not a choice](https://example.com)
+
+```
+- [Inside a fence](#shore)
+# Not a heading
+```
+
+# The sea {.death}
+
+The waves take you.
+";
+
+    fn choices(page: &Page) -> Vec<(&str, u32)> {
+        page.choices
+            .iter()
+            .map(|choice| (choice.text.as_str(), choice.target_page_id))
+            .collect()
+    }
+
+    fn error(source: &str) -> (Option<usize>, String) {
+        let error = parse_markdown(source).unwrap_err();
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn parses_headings_anchors_and_classes() {
+        assert_eq!(
+            parse_heading("The sea {#sea .death}"),
+            (
+                "The sea".to_string(),
+                "sea".to_string(),
+                vec!["death".to_string()]
+            )
+        );
+        assert_eq!(
+            parse_heading("  Lamp room  "),
+            ("Lamp room".to_string(), "lamp-room".to_string(), Vec::new())
+        );
+        assert_eq!(slug("What's up, Doc?"), "whats-up-doc");
+    }
+
+    #[test]
+    fn only_lone_page_links_are_choices() {
+        assert_eq!(
+            choice_link("  * [Climb](#lamp-room) "),
+            Some(("Climb".to_string(), "lamp-room".to_string()))
+        );
+        assert_eq!(choice_link("[Climb](#lamp-room)"), None);
+        assert_eq!(choice_link("- [Climb](https://example.com)"), None);
+        assert_eq!(choice_link("- [Climb](#lamp-room) now"), None);
+        assert_eq!(choice_link("- [a] [b](#lamp-room)"), None);
+    }
+
+    #[test]
+    fn imports_pages_choices_and_endings() {
+        let book = parse_markdown(STORY).unwrap();
+        assert_eq!(book.title, "The Lighthouse Keeper");
+        assert_eq!(book.summary, "A storm and a dark lamp.");
+        assert_eq!(book.id, id_from_name("The Lighthouse Keeper"));
+        assert_eq!(book.starting_page, 2);
+
+        let ids: Vec<_> = book.pages.iter().map(|page| page.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(book.pages[0].content, "The wick is *dry*.");
+        assert_eq!(choices(&book.pages[0]), [("Go back down", 2)]);
+        assert_eq!(
+            choices(&book.pages[1]),
+            [("Climb the stairs", 1), ("Row out to sea", 3)]
+        );
+        assert_eq!(
+            book.pages[1].content,
+            "The storm has passed.\n\n\
+             - [Not a choice](https://example.com)\n\n\
+             ```\n- [Inside a fence](#shore)\n# Not a heading\n```"
+        );
+
+        let sea = book.pages[2].ending.as_ref().unwrap();
+        assert_eq!(
+            (sea.kind, sea.title.as_str()),
+            (EndingKind::Death, "The sea")
+        );
+        assert!(book.pages[..2].iter().all(|page| page.ending.is_none()));
+    }
+
+    #[test]
+    fn starts_on_the_first_page_with_the_given_id() {
+        let book = parse_markdown("---\ntitle: Short\nid: 7\n---\n# Only\nHello.").unwrap();
+        assert_eq!((book.id, book.starting_page), (7, 1));
+        assert_eq!(book.pages[0].content, "Hello.");
+    }
+
+    #[test]
+    fn reports_front_matter_errors_with_their_line() {
+        assert_eq!(
+            error("# Start\n"),
+            (Some(1), "missing --- front matter".to_string())
+        );
+        assert_eq!(
+            error("\n---\ntitle: Open\n# Start\n"),
+            (Some(2), "front matter is not closed".to_string())
+        );
+        let (line, message) = error("---\ntitle: Typo\nsumary: oops\n---\n# Start\n");
+        assert_eq!(line, Some(3));
+        assert!(message.contains("unknown field `sumary`"), "{}", message);
+        assert_eq!(
+            error("---\ntitle: Lost\nstart: nowhere\n---\n# Start\n"),
+            (None, "start page #nowhere does not exist".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_page_errors_with_their_line() {
+        assert_eq!(
+            error("---\ntitle: Empty\n---\n\n"),
+            (None, "story has no pages".to_string())
+        );
+        assert_eq!(
+            error("---\ntitle: Early\n---\n\nToo soon.\n# Start\n"),
+            (Some(5), "text before the first page heading".to_string())
+        );
+        assert_eq!(
+            error("---\ntitle: Twice\n---\n# Start\n# Other {#start}\n"),
+            (Some(5), "page #start is defined more than once".to_string())
+        );
+        assert_eq!(
+            error("---\ntitle: Lost\n---\n# Start\n\n- [Onward](#nowhere)\n"),
+            (
+                Some(6),
+                "choice \"Onward\" links to missing page #nowhere".to_string()
+            )
+        );
+    }
+}
@@ -1,2 +1,15 @@
 pub mod auth_service;
+pub mod book_loader;
 pub mod book_service;
+pub mod book_validator;
+pub mod epub_exporter;
+pub mod ink_importer;
+pub mod login_throttle;
+pub mod markdown_importer;
+pub mod oidc_client;
+pub mod progress_service;
+pub mod session_store;
+pub mod signing_keys;
+pub mod site_exporter;
+pub mod twine_importer;
+pub mod user_repository;
@@ -0,0 +1,432 @@
+use crate::services::session_store::random_token;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+/// How long a browser has to come back from the identity provider.
+const PENDING_LOGIN_SECS: u64 = 10 * 60;
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    /// Left out for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    /// Shown on the login button, as in "Sign in with ...".
+    pub provider_name: String,
+    pub scopes: String,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Http(String),
+    Discovery(String),
+    UnknownState,
+    Provider(String),
+    InvalidIdToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Http(message) => {
+                write!(f, "request to identity provider failed: {}", message)
+            }
+            OidcError::Discovery(message) => write!(f, "bad provider configuration: {}", message),
+            OidcError::UnknownState => write!(f, "unknown or expired login attempt"),
+            OidcError::Provider(message) => write!(f, "identity provider refused: {}", message),
+            OidcError::InvalidIdToken(message) => write!(f, "invalid id token: {}", message),
+        }
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        OidcError::Http(e.to_string())
+    }
+}
+
+/// What the identity provider says about the person signing in, taken from
+/// the id token.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Identity {
+    pub iss: String,
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+    #[serde(default)]
+    nonce: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// A finished sign-in at the provider.
+pub struct OidcLogin {
+    pub identity: Identity,
+    /// The signed-in user who asked to link the identity to their account,
+    /// if this was not a plain sign-in.
+    pub link_to: Option<String>,
+}
+
+struct PendingLogin {
+    verifier: String,
+    nonce: String,
+    link_to: Option<String>,
+    created_at: u64,
+}
+
+/// An OpenID Connect relying party using the authorization code flow with
+/// PKCE. Logins in progress are kept in memory by their `state` value.
+pub struct OidcClient {
+    config: OidcConfig,
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: String,
+    jwks_uri: String,
+    jwks: RwLock<JwkSet>,
+    pending: RwLock<HashMap<String, PendingLogin>>,
+    http: reqwest::Client,
+}
+
+impl OidcClient {
+    /// Reads the provider's discovery document and signing keys.
+    pub async fn discover(config: OidcConfig) -> Result<Self, OidcError> {
+        let http = reqwest::Client::new();
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if discovery.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(OidcError::Discovery(format!(
+                "provider calls itself {}",
+                discovery.issuer
+            )));
+        }
+        let authorization_endpoint = Url::parse(&discovery.authorization_endpoint)
+            .map_err(|e| OidcError::Discovery(e.to_string()))?;
+        let jwks = fetch_jwks(&http, &discovery.jwks_uri).await?;
+
+        Ok(Self {
+            config,
+            issuer: discovery.issuer,
+            authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            jwks_uri: discovery.jwks_uri,
+            jwks: RwLock::new(jwks),
+            pending: RwLock::new(HashMap::new()),
+            http,
+        })
+    }
+
+    pub fn provider_name(&self) -> &str {
+        &self.config.provider_name
+    }
+
+    /// Starts a login, returning its `state` value, which the browser must
+    /// bring back, and the provider URL to send the browser to. `link_to`
+    /// names the signed-in user to link the identity to instead of signing
+    /// in with it.
+    pub fn begin(&self, now: u64, link_to: Option<&str>) -> (String, String) {
+        let state = random_token();
+        let nonce = random_token();
+        let verifier = random_token();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let mut url = self.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        let mut pending = self.pending.write().unwrap();
+        pending.retain(|_, p| p.created_at + PENDING_LOGIN_SECS > now);
+        pending.insert(
+            state.clone(),
+            PendingLogin {
+                verifier,
+                nonce,
+                link_to: link_to.map(str::to_string),
+                created_at: now,
+            },
+        );
+        (state, url.to_string())
+    }
+
+    /// Completes the login started with `state`, exchanging the code for an
+    /// id token and checking it.
+    pub async fn finish(&self, state: &str, code: &str, now: u64) -> Result<OidcLogin, OidcError> {
+        let pending = self
+            .pending
+            .write()
+            .unwrap()
+            .remove(state)
+            .filter(|p| p.created_at + PENDING_LOGIN_SECS > now)
+            .ok_or(OidcError::UnknownState)?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &pending.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = self
+            .http
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(OidcError::Provider(format!(
+                "token endpoint returned {}: {}",
+                status, body
+            )));
+        }
+        let tokens: TokenResponse = response.json().await?;
+
+        let identity = self.verify_id_token(&tokens.id_token).await?;
+        if identity.nonce.as_deref() != Some(pending.nonce.as_str()) {
+            return Err(OidcError::InvalidIdToken(
+                "nonce does not match".to_string(),
+            ));
+        }
+        Ok(OidcLogin {
+            identity,
+            link_to: pending.link_to,
+        })
+    }
+
+    async fn verify_id_token(&self, token: &str) -> Result<Identity, OidcError> {
+        let header = decode_header(token).map_err(invalid_id_token)?;
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = self.config.client_secret.as_ref().ok_or_else(|| {
+                    OidcError::InvalidIdToken("signed with a client secret we do not have".into())
+                })?;
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            _ => self.signing_key(header.kid.as_deref()).await?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        decode::<Identity>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(invalid_id_token)
+    }
+
+    /// Finds the provider key with id `kid`, fetching the key set again if
+    /// it is not there, since providers rotate keys.
+    async fn signing_key(&self, kid: Option<&str>) -> Result<DecodingKey, OidcError> {
+        if let Some(key) = self.find_key(kid) {
+            return key;
+        }
+        let jwks = fetch_jwks(&self.http, &self.jwks_uri).await?;
+        *self.jwks.write().unwrap() = jwks;
+        self.find_key(kid).unwrap_or_else(|| {
+            Err(OidcError::InvalidIdToken(
+                "no matching signing key".to_string(),
+            ))
+        })
+    }
+
+    fn find_key(&self, kid: Option<&str>) -> Option<Result<DecodingKey, OidcError>> {
+        let jwks = self.jwks.read().unwrap();
+        let jwk = match kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }?;
+        Some(DecodingKey::from_jwk(jwk).map_err(invalid_id_token))
+    }
+}
+
+async fn fetch_jwks(http: &reqwest::Client, uri: &str) -> Result<JwkSet, OidcError> {
+    Ok(http
+        .get(uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+fn invalid_id_token(e: jsonwebtoken::errors::Error) -> OidcError {
+    OidcError::InvalidIdToken(e.to_string())
+}
+
+// The mock provider's `main` only runs as the example.
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../examples/mock_oidc.rs"]
+mod mock_oidc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user::Role;
+    use crate::services::auth_service::{now_secs, AuthError, AuthService, TokenLifetimes};
+    use crate::services::login_throttle::LoginThrottle;
+    use crate::services::session_store::SessionStore;
+    use crate::services::signing_keys::SigningKeys;
+    use crate::services::user_repository::InMemoryUserRepository;
+    use std::sync::Arc;
+
+    /// Serves the mock provider on a spare port and discovers it.
+    async fn mock_provider() -> OidcClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let app = mock_oidc::router(&issuer);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        OidcClient::discover(OidcConfig {
+            issuer,
+            client_id: "storybook".to_string(),
+            client_secret: Some("mock-secret".to_string()),
+            redirect_url: "http://localhost:3000/auth/oidc/callback".to_string(),
+            provider_name: "Mock IdP".to_string(),
+            scopes: "openid email profile".to_string(),
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Signs in at the provider as `name`, as a browser would, and finishes
+    /// the login with the code it sends back.
+    async fn sign_in(client: &OidcClient, name: &str, link_to: Option<&str>) -> OidcLogin {
+        let (state, url) = client.begin(now_secs(), link_to);
+        let mut url = Url::parse(&url).unwrap();
+        url.query_pairs_mut().append_pair("username", name);
+        let browser = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = browser.get(url).send().await.unwrap();
+        let location = response.headers()["location"].to_str().unwrap();
+        let callback = Url::parse(location).unwrap();
+        let query: HashMap<_, _> = callback.query_pairs().collect();
+        assert_eq!(query["state"], state);
+        client
+            .finish(&state, &query["code"], now_secs())
+            .await
+            .unwrap()
+    }
+
+    fn auth_service() -> AuthService {
+        AuthService::new(
+            SigningKeys::hmac(b"test secret"),
+            Arc::new(InMemoryUserRepository::new()),
+            SessionStore::new(),
+            TokenLifetimes {
+                access_secs: 60,
+                refresh_secs: 600,
+            },
+            LoginThrottle::in_memory(),
+        )
+    }
+
+    #[tokio::test]
+    async fn first_sign_in_creates_a_reader_that_later_sign_ins_find() {
+        let client = mock_provider().await;
+        let auth = auth_service();
+
+        let login = sign_in(&client, "alice", None).await;
+        assert_eq!(login.identity.sub, "mock|alice");
+        assert_eq!(login.link_to, None);
+        let user = auth.login_with_identity(&login.identity).unwrap();
+        assert_eq!(user.username, "alice");
+        assert_eq!(user.role, Role::Reader);
+
+        let again = sign_in(&client, "alice", None).await;
+        assert_eq!(
+            auth.login_with_identity(&again.identity).unwrap().username,
+            "alice"
+        );
+    }
+
+    #[tokio::test]
+    async fn identity_named_like_an_admin_gets_a_new_account() {
+        let client = mock_provider().await;
+        let auth = auth_service();
+        auth.create_user("admin", "admin password 1", Role::Admin)
+            .unwrap();
+
+        let login = sign_in(&client, "admin", None).await;
+        let user = auth.login_with_identity(&login.identity).unwrap();
+        assert_ne!(user.username, "admin");
+        assert!(user.username.starts_with("admin-"));
+        assert_eq!(user.role, Role::Reader);
+
+        let again = auth.login_with_identity(&login.identity).unwrap();
+        assert_eq!(again.username, user.username);
+    }
+
+    #[tokio::test]
+    async fn signed_in_user_can_link_an_identity() {
+        let client = mock_provider().await;
+        let auth = auth_service();
+        auth.create_user("bob", "bob password 1", Role::Author)
+            .unwrap();
+
+        let login = sign_in(&client, "robert", Some("bob")).await;
+        assert_eq!(login.link_to.as_deref(), Some("bob"));
+        auth.link_identity("bob", &login.identity).unwrap();
+
+        let later = sign_in(&client, "robert", None).await;
+        let user = auth.login_with_identity(&later.identity).unwrap();
+        assert_eq!(user.username, "bob");
+        assert_eq!(user.role, Role::Author);
+    }
+
+    #[tokio::test]
+    async fn identity_linked_to_someone_else_cannot_be_linked() {
+        let client = mock_provider().await;
+        let auth = auth_service();
+        auth.create_user("admin", "admin password 1", Role::Admin)
+            .unwrap();
+
+        let login = sign_in(&client, "carol", None).await;
+        auth.login_with_identity(&login.identity).unwrap();
+        assert!(matches!(
+            auth.link_identity("admin", &login.identity),
+            Err(AuthError::IdentityInUse)
+        ));
+    }
+}
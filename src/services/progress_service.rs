@@ -0,0 +1,390 @@
+use crate::models::book::{Book, Page};
+use crate::models::progress::{ProgressRecord, ReaderProgress};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+
+/// Tracks where each reader is in each book, keyed by the JWT `sub`.
+/// When created with `from_file`, every change is written back to disk.
+#[derive(Default)]
+pub struct ProgressService {
+    file: Option<Arc<ProgressFile>>,
+    progress: RwLock<HashMap<(String, u32), ReaderProgress>>,
+    /// How many snapshots of `progress` have been taken for saving.
+    snapshots: AtomicU64,
+}
+
+/// The file progress is saved to. Saves may run in any order, so each
+/// carries the number of its snapshot and one older than the last written
+/// is dropped rather than put back over newer progress.
+struct ProgressFile {
+    path: PathBuf,
+    written: Mutex<u64>,
+}
+
+impl ProgressFile {
+    fn write(&self, snapshot: u64, json: &str) {
+        let mut written = self.written.lock().unwrap();
+        if snapshot <= *written {
+            return;
+        }
+        // Written next to the file and renamed over it, so a crash part way
+        // through leaves the old progress rather than half of the new.
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let result = File::create(&temp)
+            .and_then(|mut file| {
+                file.write_all(json.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&temp, &self.path));
+        match result {
+            Ok(()) => *written = snapshot,
+            Err(e) => log::error!("Failed to save progress to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+impl ProgressService {
+    pub fn from_file(path: PathBuf) -> Self {
+        let mut progress = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(source) => match serde_json::from_str::<Vec<ProgressRecord>>(&source) {
+                Ok(records) => {
+                    for record in records {
+                        progress.insert((record.username, record.book_id), record.progress);
+                    }
+                }
+                Err(e) => log::error!("Ignoring progress file {}: {}", path.display(), e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("Failed to read progress file {}: {}", path.display(), e),
+        }
+        Self {
+            file: Some(Arc::new(ProgressFile {
+                path,
+                written: Mutex::new(0),
+            })),
+            progress: RwLock::new(progress),
+            snapshots: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get_progress(&self, username: &str, book_id: u32) -> ReaderProgress {
+        self.progress
+            .read()
+            .unwrap()
+            .get(&(username.to_string(), book_id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Begins a new playthrough of `book` on `page`, resetting flags,
+    /// counters and inventory to the book's initial state.
+    pub fn start(&self, username: &str, book: &Book, page: &Page) {
+        self.update(username, book.id, |progress| {
+            progress.current_page = Some(page.id);
+            progress.state = book.initial_state.clone();
+            if page.ending.is_some() {
+                progress.found_endings.insert(page.id);
+            }
+        });
+    }
+
+    /// Takes the choice at `choice_index` on page `page_id` if the reader is
+    /// on that page and the choice's conditions are met, applying its
+    /// effects and recording the ending if it leads to one. This is the only
+    /// way a reader moves between pages. Returns the target page id when the
+    /// choice was taken.
+    pub fn take_choice(
+        &self,
+        username: &str,
+        book: &Book,
+        page_id: u32,
+        choice_index: usize,
+    ) -> Option<u32> {
+        let page = book.pages.iter().find(|page| page.id == page_id)?;
+        let choice = page.choices.get(choice_index)?;
+        let target = book
+            .pages
+            .iter()
+            .find(|page| page.id == choice.target_page_id)?;
+        let mut taken = false;
+        self.update(username, book.id, |progress| {
+            if progress.current_page != Some(page.id) || !progress.state.is_available(choice) {
+                return;
+            }
+            for effect in &choice.effects {
+                progress.state.apply(effect);
+            }
+            progress.current_page = Some(target.id);
+            if target.ending.is_some() {
+                progress.found_endings.insert(target.id);
+            }
+            taken = true;
+        });
+        taken.then_some(target.id)
+    }
+
+    pub fn reset(&self, username: &str, book_id: u32) {
+        self.update(username, book_id, |progress| {
+            progress.current_page = None;
+            progress.state = Default::default();
+        });
+    }
+
+    /// Removes every book's progress for a reader, e.g. when their account is deleted.
+    pub fn forget_reader(&self, username: &str) {
+        let mut progress = self.progress.write().unwrap();
+        progress.retain(|(reader, _), _| reader != username);
+        self.save(progress);
+    }
+
+    pub fn get_found_endings(&self, username: &str, book_id: u32) -> HashSet<u32> {
+        self.get_progress(username, book_id).found_endings
+    }
+
+    fn update(&self, username: &str, book_id: u32, f: impl FnOnce(&mut ReaderProgress)) {
+        let mut progress = self.progress.write().unwrap();
+        f(progress.entry((username.to_string(), book_id)).or_default());
+        self.save(progress);
+    }
+
+    fn to_records(progress: &HashMap<(String, u32), ReaderProgress>) -> Vec<ProgressRecord> {
+        progress
+            .iter()
+            .map(|((username, book_id), progress)| ProgressRecord {
+                username: username.clone(),
+                book_id: *book_id,
+                progress: progress.clone(),
+            })
+            .collect()
+    }
+
+    /// Takes a numbered snapshot of `progress`, then lets go of the lock and
+    /// writes it on the blocking thread pool, or straight away outside the
+    /// async runtime.
+    fn save(&self, progress: RwLockWriteGuard<HashMap<(String, u32), ReaderProgress>>) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let json = match serde_json::to_string_pretty(&Self::to_records(&progress)) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("Failed to save progress: {}", e);
+                return;
+            }
+        };
+        let snapshot = self.snapshots.fetch_add(1, Ordering::SeqCst) + 1;
+        drop(progress);
+
+        let file = file.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || file.write(snapshot, &json));
+            }
+            Err(_) => file.write(snapshot, &json),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::book::{Choice, Condition, Effect, Ending, EndingKind, UnmetChoice};
+    use crate::models::progress::PlaythroughState;
+
+    fn choice(target_page_id: u32, conditions: Vec<Condition>, effects: Vec<Effect>) -> Choice {
+        Choice {
+            text: format!("Go to {}", target_page_id),
+            target_page_id,
+            conditions,
+            effects,
+            when_unmet: UnmetChoice::Hidden,
+        }
+    }
+
+    fn page(id: u32, choices: Vec<Choice>, ending: Option<EndingKind>) -> Page {
+        Page {
+            id,
+            content: String::new(),
+            choices,
+            ending: ending.map(|kind| Ending {
+                kind,
+                title: format!("Ending {}", id),
+            }),
+        }
+    }
+
+    /// Page 1 leads to the key on page 2 and, with the key, to the treasure
+    /// ending on page 3.
+    fn book() -> Book {
+        let key = || Condition::HasItem {
+            item: "key".to_string(),
+        };
+        Book {
+            id: 7,
+            title: "Vault".to_string(),
+            summary: String::new(),
+            starting_page: 1,
+            initial_state: PlaythroughState::default(),
+            pages: vec![
+                page(
+                    1,
+                    vec![choice(2, vec![], vec![]), choice(3, vec![key()], vec![])],
+                    None,
+                ),
+                page(
+                    2,
+                    vec![choice(
+                        1,
+                        vec![],
+                        vec![Effect::AddItem {
+                            item: "key".to_string(),
+                        }],
+                    )],
+                    None,
+                ),
+                page(3, vec![], Some(EndingKind::Victory)),
+            ],
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mustachestory-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn progress_is_saved_and_loaded_again() {
+        let path = temp_path("progress-saved");
+        let book = book();
+        let progress = ProgressService::from_file(path.clone());
+        progress.start("alice", &book, &book.pages[0]);
+        progress.take_choice("alice", &book, 1, 0);
+
+        let reloaded = ProgressService::from_file(path.clone());
+        assert_eq!(
+            reloaded.get_progress("alice", book.id).current_page,
+            Some(2)
+        );
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        assert!(!PathBuf::from(temp).exists());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn older_snapshots_do_not_overwrite_newer_ones() {
+        let path = temp_path("progress-order");
+        let file = ProgressFile {
+            path: path.clone(),
+            written: Mutex::new(0),
+        };
+        file.write(2, "[\"newer\"]");
+        file.write(1, "[\"older\"]");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[\"newer\"]");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn saves_from_the_runtime_end_with_the_latest_progress() {
+        let path = temp_path("progress-runtime");
+        let book = book();
+        let progress = ProgressService::from_file(path.clone());
+        progress.start("alice", &book, &book.pages[0]);
+        for _ in 0..20 {
+            progress.take_choice("alice", &book, 1, 0);
+            progress.take_choice("alice", &book, 2, 0);
+        }
+        progress.take_choice("alice", &book, 1, 1);
+
+        // Let the blocking pool finish the queued writes.
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        let reloaded = ProgressService::from_file(path.clone());
+        assert_eq!(
+            reloaded.get_progress("alice", book.id).current_page,
+            Some(3)
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn playthroughs_start_from_the_book_initial_state() {
+        let mut book = book();
+        book.initial_state.inventory.insert("key".to_string());
+        let progress = ProgressService::default();
+        progress.start("alice", &book, &book.pages[0]);
+        assert_eq!(progress.take_choice("alice", &book, 1, 1), Some(3));
+
+        // Starting over resets the playthrough to the initial state too.
+        progress.start("alice", &book, &book.pages[0]);
+        progress.take_choice("alice", &book, 1, 0);
+        progress.take_choice("alice", &book, 2, 0);
+        book.initial_state = PlaythroughState::default();
+        progress.start("alice", &book, &book.pages[0]);
+        let reader = progress.get_progress("alice", book.id);
+        assert!(reader.state.is_empty());
+        assert_eq!(progress.take_choice("alice", &book, 1, 1), None);
+    }
+
+    #[test]
+    fn choices_move_the_reader_and_apply_effects() {
+        let book = book();
+        let progress = ProgressService::default();
+        progress.start("alice", &book, &book.pages[0]);
+
+        assert_eq!(progress.take_choice("alice", &book, 1, 0), Some(2));
+        assert_eq!(progress.take_choice("alice", &book, 2, 0), Some(1));
+        let reader = progress.get_progress("alice", book.id);
+        assert_eq!(reader.current_page, Some(1));
+        assert!(reader.state.inventory.contains("key"));
+    }
+
+    #[test]
+    fn unmet_conditions_and_other_pages_are_refused() {
+        let book = book();
+        let progress = ProgressService::default();
+        progress.start("alice", &book, &book.pages[0]);
+
+        assert_eq!(progress.take_choice("alice", &book, 1, 1), None);
+        assert_eq!(progress.take_choice("alice", &book, 2, 0), None);
+        assert_eq!(progress.take_choice("alice", &book, 1, 5), None);
+        assert_eq!(
+            progress.get_progress("alice", book.id).current_page,
+            Some(1)
+        );
+        assert_eq!(progress.take_choice("bob", &book, 1, 0), None);
+    }
+
+    #[test]
+    fn endings_are_recorded_when_reached() {
+        let book = book();
+        let progress = ProgressService::default();
+        progress.start("alice", &book, &book.pages[0]);
+        progress.take_choice("alice", &book, 1, 0);
+        progress.take_choice("alice", &book, 2, 0);
+        assert!(progress.get_found_endings("alice", book.id).is_empty());
+
+        assert_eq!(progress.take_choice("alice", &book, 1, 1), Some(3));
+        assert_eq!(
+            progress.get_found_endings("alice", book.id),
+            HashSet::from([3])
+        );
+
+        // Starting again keeps the endings found so far.
+        progress.start("alice", &book, &book.pages[0]);
+        let reader = progress.get_progress("alice", book.id);
+        assert_eq!(reader.current_page, Some(1));
+        assert!(reader.state.inventory.is_empty());
+        assert!(reader.found_endings.contains(&3));
+    }
+}
@@ -0,0 +1,555 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+/// How long a used refresh token is still accepted, so that concurrent
+/// requests carrying the same token do not look like token theft.
+const REUSE_GRACE_SECS: u64 = 30;
+
+/// How stale a session's `last_seen` may get before a request updates it,
+/// so that most requests only need to read the store.
+const LAST_SEEN_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub username: String,
+    pub device: String,
+    pub created_at: u64,
+    pub last_seen: u64,
+}
+
+struct Session {
+    info: SessionInfo,
+    expires_at: u64,
+    /// Refresh token hashes, with the time each was used.
+    refresh_tokens: HashMap<String, Option<u64>>,
+    /// Ids and expiry times of access tokens issued in this session.
+    access_tokens: Vec<(String, u64)>,
+}
+
+/// Signed-in sessions, one per login. Each session has a chain of single-use
+/// refresh tokens, of which only hashes are kept: refreshing marks the old
+/// token used and issues a new one, and presenting a used token again after
+/// a short grace period ends the session. Ending a session also revokes the
+/// access tokens issued in it until they expire.
+///
+/// Sessions are kept in memory and, when opened on a SQLite database, every
+/// change is also written there, so sessions and revocations survive a
+/// restart.
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: RwLock<HashMap<String, Session>>,
+    revoked_access_tokens: RwLock<HashMap<String, u64>>,
+    database: Option<Mutex<Connection>>,
+}
+
+impl SessionStore {
+    /// A store that keeps sessions in memory only.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store backed by the SQLite database at `path`, loading the
+    /// sessions and revoked access tokens that have not yet expired.
+    pub fn open(path: &Path, now: u64) -> rusqlite::Result<Self> {
+        let mut connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                username TEXT NOT NULL,
+                device TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_seen INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS refresh_tokens (
+                hash TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                used_at INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS access_tokens (
+                jti TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS revoked_access_tokens (
+                jti TEXT PRIMARY KEY,
+                expires_at INTEGER NOT NULL
+            );",
+        )?;
+        let transaction = connection.transaction()?;
+        delete_expired(&transaction, now)?;
+        transaction.execute(
+            "DELETE FROM access_tokens WHERE expires_at <= ?1",
+            params![now],
+        )?;
+        transaction.execute(
+            "DELETE FROM revoked_access_tokens WHERE expires_at <= ?1",
+            params![now],
+        )?;
+        transaction.commit()?;
+        let (sessions, revoked_access_tokens) = load(&connection)?;
+        Ok(Self {
+            sessions: RwLock::new(sessions),
+            revoked_access_tokens: RwLock::new(revoked_access_tokens),
+            database: Some(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `write` in a transaction on the database, if there is one.
+    /// Callers hold the lock on whatever in-memory state they changed, so
+    /// the database sees changes in the same order. A failed write is
+    /// logged; the change still holds until the server restarts.
+    fn persist(&self, write: impl FnOnce(&Connection) -> rusqlite::Result<()>) {
+        let Some(database) = &self.database else {
+            return;
+        };
+        let mut connection = database.lock().unwrap();
+        let written = connection.transaction().and_then(|transaction| {
+            write(&transaction)?;
+            transaction.commit()
+        });
+        if let Err(e) = written {
+            log::error!("Failed to save sessions: {}", e);
+        }
+    }
+
+    /// Starts a session, returning its id and first refresh token.
+    pub fn create(&self, username: &str, device: &str, now: u64, ttl: u64) -> (String, String) {
+        let id = random_token();
+        let refresh_token = random_token();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, s| s.expires_at > now);
+        let session = Session {
+            info: SessionInfo {
+                id: id.clone(),
+                username: username.to_string(),
+                device: device.to_string(),
+                created_at: now,
+                last_seen: now,
+            },
+            expires_at: now + ttl,
+            refresh_tokens: HashMap::from([(hash_token(&refresh_token), None)]),
+            access_tokens: Vec::new(),
+        };
+        self.persist(|connection| {
+            delete_expired(connection, now)?;
+            connection.execute(
+                "INSERT INTO sessions (id, username, device, created_at, last_seen, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?4, ?5)",
+                params![id, username, device, now, session.expires_at],
+            )?;
+            connection.execute(
+                "INSERT INTO refresh_tokens (hash, session_id) VALUES (?1, ?2)",
+                params![hash_token(&refresh_token), id],
+            )?;
+            Ok(())
+        });
+        sessions.insert(id.clone(), session);
+        (id, refresh_token)
+    }
+
+    /// Exchanges a refresh token for a new one, returning the session it
+    /// belongs to. A token reused within the grace period is accepted but
+    /// gets no replacement, since one was already handed out.
+    pub fn rotate(&self, token: &str, now: u64, ttl: u64) -> Option<(SessionInfo, Option<String>)> {
+        let hash = hash_token(token);
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions
+            .values_mut()
+            .find(|s| s.refresh_tokens.contains_key(&hash))?;
+        if session.expires_at <= now {
+            return None;
+        }
+        match session.refresh_tokens[&hash] {
+            Some(used_at) if now <= used_at + REUSE_GRACE_SECS => {
+                session.info.last_seen = now;
+                let info = session.info.clone();
+                self.persist(|connection| touch(connection, &info.id, now));
+                return Some((info, None));
+            }
+            Some(_) => {
+                log::warn!(
+                    "Refresh token reused for {}, ending the session",
+                    session.info.username
+                );
+                let id = session.info.id.clone();
+                drop(sessions);
+                self.end(&id, now);
+                return None;
+            }
+            None => {}
+        }
+
+        let refresh_token = random_token();
+        let new_hash = hash_token(&refresh_token);
+        self.persist(|connection| {
+            connection.execute(
+                "UPDATE refresh_tokens SET used_at = ?2 WHERE hash = ?1",
+                params![hash, now],
+            )?;
+            connection.execute(
+                "INSERT INTO refresh_tokens (hash, session_id) VALUES (?1, ?2)",
+                params![new_hash, session.info.id],
+            )?;
+            connection.execute(
+                "UPDATE sessions SET last_seen = ?2, expires_at = ?3 WHERE id = ?1",
+                params![session.info.id, now, now + ttl],
+            )?;
+            Ok(())
+        });
+        session.refresh_tokens.insert(hash, Some(now));
+        session.refresh_tokens.insert(new_hash, None);
+        session.expires_at = now + ttl;
+        session.info.last_seen = now;
+        Some((session.info.clone(), Some(refresh_token)))
+    }
+
+    /// Remembers an access token issued in `session_id`, so it can be revoked
+    /// along with the session.
+    pub fn record_access_token(&self, session_id: &str, jti: &str, now: u64, expires_at: u64) {
+        if let Some(session) = self.sessions.write().unwrap().get_mut(session_id) {
+            session
+                .access_tokens
+                .retain(|(_, token_expires_at)| *token_expires_at > now);
+            session.access_tokens.push((jti.to_string(), expires_at));
+            self.persist(|connection| {
+                connection.execute(
+                    "DELETE FROM access_tokens WHERE session_id = ?1 AND expires_at <= ?2",
+                    params![session_id, now],
+                )?;
+                connection.execute(
+                    "INSERT OR REPLACE INTO access_tokens (jti, session_id, expires_at)
+                     VALUES (?1, ?2, ?3)",
+                    params![jti, session_id, expires_at],
+                )?;
+                Ok(())
+            });
+        }
+    }
+
+    /// Whether the access token `jti` from session `session_id` may be used:
+    /// only while the session is still going and the token has not been
+    /// revoked. Marks the session as seen, at most once a minute.
+    pub fn check_access_token(&self, session_id: &str, jti: &str, now: u64) -> bool {
+        if self.revoked_access_tokens.read().unwrap().contains_key(jti) {
+            return false;
+        }
+        let sessions = self.sessions.read().unwrap();
+        let Some(session) = sessions.get(session_id) else {
+            return false;
+        };
+        if session.expires_at <= now {
+            return false;
+        }
+        if now < session.info.last_seen + LAST_SEEN_INTERVAL_SECS {
+            return true;
+        }
+        drop(sessions);
+        if let Some(session) = self.sessions.write().unwrap().get_mut(session_id) {
+            session.info.last_seen = now;
+            self.persist(|connection| touch(connection, session_id, now));
+        }
+        true
+    }
+
+    /// The session a refresh token belongs to.
+    pub fn find_by_refresh_token(&self, token: &str) -> Option<SessionInfo> {
+        let hash = hash_token(token);
+        self.sessions
+            .read()
+            .unwrap()
+            .values()
+            .find(|s| s.refresh_tokens.contains_key(&hash))
+            .map(|s| s.info.clone())
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<SessionInfo> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(session_id)
+            .map(|s| s.info.clone())
+    }
+
+    /// The user's unexpired sessions, most recently seen first.
+    pub fn list(&self, username: &str, now: u64) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|s| s.info.username == username && s.expires_at > now)
+            .map(|s| s.info.clone())
+            .collect();
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen));
+        sessions
+    }
+
+    /// Ends a session and revokes its access tokens.
+    pub fn end(&self, session_id: &str, now: u64) {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(session) = sessions.remove(session_id) else {
+            return;
+        };
+        let mut revoked = self.revoked_access_tokens.write().unwrap();
+        revoked.retain(|_, expires_at| *expires_at > now);
+        let newly_revoked: Vec<_> = session
+            .access_tokens
+            .into_iter()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .collect();
+        self.persist(|connection| {
+            delete_session(connection, session_id)?;
+            connection.execute(
+                "DELETE FROM revoked_access_tokens WHERE expires_at <= ?1",
+                params![now],
+            )?;
+            for (jti, expires_at) in &newly_revoked {
+                connection.execute(
+                    "INSERT OR REPLACE INTO revoked_access_tokens (jti, expires_at)
+                     VALUES (?1, ?2)",
+                    params![jti, expires_at],
+                )?;
+            }
+            Ok(())
+        });
+        revoked.extend(newly_revoked);
+    }
+
+    pub fn end_all(&self, username: &str, now: u64) {
+        let ids: Vec<String> = self
+            .sessions
+            .read()
+            .unwrap()
+            .values()
+            .filter(|s| s.info.username == username)
+            .map(|s| s.info.id.clone())
+            .collect();
+        for id in ids {
+            self.end(&id, now);
+        }
+    }
+}
+
+fn touch(connection: &Connection, session_id: &str, now: u64) -> rusqlite::Result<()> {
+    connection.execute(
+        "UPDATE sessions SET last_seen = ?2 WHERE id = ?1",
+        params![session_id, now],
+    )?;
+    Ok(())
+}
+
+fn delete_session(connection: &Connection, session_id: &str) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
+    connection.execute(
+        "DELETE FROM refresh_tokens WHERE session_id = ?1",
+        params![session_id],
+    )?;
+    connection.execute(
+        "DELETE FROM access_tokens WHERE session_id = ?1",
+        params![session_id],
+    )?;
+    Ok(())
+}
+
+fn delete_expired(connection: &Connection, now: u64) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare("SELECT id FROM sessions WHERE expires_at <= ?1")?;
+    let expired = statement
+        .query_map(params![now], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for id in expired {
+        delete_session(connection, &id)?;
+    }
+    Ok(())
+}
+
+/// The sessions and revoked access tokens saved in the database.
+fn load(
+    connection: &Connection,
+) -> rusqlite::Result<(HashMap<String, Session>, HashMap<String, u64>)> {
+    let mut sessions = HashMap::new();
+    let mut statement = connection
+        .prepare("SELECT id, username, device, created_at, last_seen, expires_at FROM sessions")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let id: String = row.get(0)?;
+        sessions.insert(
+            id.clone(),
+            Session {
+                info: SessionInfo {
+                    id,
+                    username: row.get(1)?,
+                    device: row.get(2)?,
+                    created_at: row.get(3)?,
+                    last_seen: row.get(4)?,
+                },
+                expires_at: row.get(5)?,
+                refresh_tokens: HashMap::new(),
+                access_tokens: Vec::new(),
+            },
+        );
+    }
+
+    let mut statement =
+        connection.prepare("SELECT hash, session_id, used_at FROM refresh_tokens")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let session_id: String = row.get(1)?;
+        if let Some(session) = sessions.get_mut(&session_id) {
+            session.refresh_tokens.insert(row.get(0)?, row.get(2)?);
+        }
+    }
+
+    let mut statement =
+        connection.prepare("SELECT jti, session_id, expires_at FROM access_tokens")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let session_id: String = row.get(1)?;
+        if let Some(session) = sessions.get_mut(&session_id) {
+            session.access_tokens.push((row.get(0)?, row.get(2)?));
+        }
+    }
+
+    let mut statement = connection.prepare("SELECT jti, expires_at FROM revoked_access_tokens")?;
+    let revoked = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok((sessions, revoked))
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const TTL: u64 = 1000;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mustachestory-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn access_tokens_need_a_live_session() {
+        let store = SessionStore::new();
+        assert!(!store.check_access_token("no such session", "jti", 100));
+
+        let (id, _) = store.create("alice", "firefox", 100, TTL);
+        store.record_access_token(&id, "jti", 100, 160);
+        assert!(store.check_access_token(&id, "jti", 120));
+        assert!(!store.check_access_token(&id, "jti", 100 + TTL));
+    }
+
+    #[test]
+    fn ending_a_session_revokes_its_access_tokens() {
+        let store = SessionStore::new();
+        let (id, _) = store.create("alice", "firefox", 100, TTL);
+        let (other, _) = store.create("alice", "phone", 100, TTL);
+        store.record_access_token(&id, "first", 100, 160);
+        store.record_access_token(&other, "second", 100, 160);
+
+        store.end_all("alice", 110);
+        assert!(!store.check_access_token(&id, "first", 120));
+        assert!(!store.check_access_token(&other, "second", 120));
+        assert!(store.list("alice", 120).is_empty());
+        assert!(store
+            .revoked_access_tokens
+            .read()
+            .unwrap()
+            .contains_key("first"));
+    }
+
+    #[test]
+    fn last_seen_is_updated_at_most_once_a_minute() {
+        let store = SessionStore::new();
+        let (id, _) = store.create("alice", "firefox", 100, TTL);
+
+        assert!(store.check_access_token(&id, "jti", 100 + LAST_SEEN_INTERVAL_SECS - 1));
+        assert_eq!(store.get(&id).unwrap().last_seen, 100);
+        assert!(store.check_access_token(&id, "jti", 100 + LAST_SEEN_INTERVAL_SECS));
+        assert_eq!(
+            store.get(&id).unwrap().last_seen,
+            100 + LAST_SEEN_INTERVAL_SECS
+        );
+    }
+
+    #[test]
+    fn reusing_a_refresh_token_after_the_grace_period_ends_the_session() {
+        let store = SessionStore::new();
+        let (id, first) = store.create("alice", "firefox", 100, TTL);
+
+        let (_, second) = store.rotate(&first, 110, TTL).unwrap();
+        assert!(second.is_some());
+        let (_, none) = store.rotate(&first, 110 + REUSE_GRACE_SECS, TTL).unwrap();
+        assert!(none.is_none());
+        assert!(store.rotate(&first, 111 + REUSE_GRACE_SECS, TTL).is_none());
+        assert!(store.get(&id).is_none());
+    }
+
+    #[test]
+    fn sessions_and_revocations_survive_reopening() {
+        let path = temp_path("sessions");
+        let (kept, ended, refresh_token) = {
+            let store = SessionStore::open(&path, 100).unwrap();
+            let (kept, refresh_token) = store.create("alice", "firefox", 100, TTL);
+            let (ended, _) = store.create("alice", "phone", 100, TTL);
+            store.record_access_token(&ended, "revoked", 100, 160);
+            store.end(&ended, 110);
+            (kept, ended, refresh_token)
+        };
+
+        let store = SessionStore::open(&path, 120).unwrap();
+        let sessions = store.list("alice", 120);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, kept);
+        assert_eq!(sessions[0].device, "firefox");
+        assert!(!store.check_access_token(&ended, "revoked", 120));
+
+        let (session, next) = store.rotate(&refresh_token, 130, TTL).unwrap();
+        assert_eq!(session.id, kept);
+        let next = next.unwrap();
+        drop(store);
+
+        // The used token and its replacement are remembered too.
+        let store = SessionStore::open(&path, 140).unwrap();
+        assert!(store.rotate(&refresh_token, 200, TTL).is_none());
+        assert!(store.get(&kept).is_none());
+        assert!(store.find_by_refresh_token(&next).is_none());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn expired_sessions_are_not_loaded() {
+        let path = temp_path("expired-sessions");
+        {
+            let store = SessionStore::open(&path, 100).unwrap();
+            store.create("alice", "firefox", 100, TTL);
+        }
+
+        let store = SessionStore::open(&path, 100 + TTL).unwrap();
+        assert!(store.list("alice", 100).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
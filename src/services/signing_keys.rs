@@ -0,0 +1,428 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use simple_asn1::{oid, ASN1Block};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum KeyError {
+    Io(PathBuf, std::io::Error),
+    Invalid(PathBuf, String),
+    NoSigningKey,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            KeyError::Invalid(path, message) => write!(f, "{}: {}", path.display(), message),
+            KeyError::NoSigningKey => write!(f, "the first key must be a private key"),
+        }
+    }
+}
+
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    /// The public key as published in our JWKS, absent for HMAC secrets.
+    jwk: Option<Value>,
+    /// For a key that has been taken out of the configuration, when the
+    /// last token it signed expires.
+    retired_until: Option<u64>,
+}
+
+/// The keys our tokens are signed and checked with. One key signs; every
+/// key, including retired ones kept for a while, verifies, picked by the
+/// `kid` in the token header, so keys can be rotated without invalidating
+/// tokens already handed out.
+pub struct SigningKeys {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+impl SigningKeys {
+    /// A single shared HMAC secret, used when no key files are configured.
+    pub fn hmac(secret: &[u8]) -> Self {
+        Self {
+            kid: None,
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            verification_keys: vec![VerificationKey {
+                kid: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret),
+                jwk: None,
+                retired_until: None,
+            }],
+        }
+    }
+
+    /// Loads RSA or Ed25519 keys from PEM files. The first file is the
+    /// private key new tokens are signed with; the rest may be private or
+    /// public keys that are only used to verify. Each key's `kid` is its
+    /// file name up to the first dot, so `2026-10.pub.pem` is `2026-10`.
+    pub fn from_pem_files(paths: &[PathBuf]) -> Result<Self, KeyError> {
+        let mut signing = None;
+        let mut verification_keys = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            let contents = std::fs::read(path).map_err(|e| KeyError::Io(path.clone(), e))?;
+            let kid = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('.').next())
+                .unwrap_or_default()
+                .to_string();
+            let loaded = load_pem(path, &contents)?;
+            let algorithm = loaded.public.algorithm();
+            if i == 0 {
+                signing = Some((
+                    loaded.encoding_key.ok_or(KeyError::NoSigningKey)?,
+                    algorithm,
+                ));
+            }
+            verification_keys.push(VerificationKey {
+                jwk: Some(jwk(&kid, &loaded.public)),
+                kid: Some(kid),
+                algorithm,
+                key: loaded
+                    .public
+                    .decoding_key()
+                    .map_err(|e| KeyError::Invalid(path.clone(), e.to_string()))?,
+                retired_until: None,
+            });
+        }
+
+        let (encoding_key, algorithm) = signing.ok_or(KeyError::NoSigningKey)?;
+        Ok(Self {
+            kid: verification_keys[0].kid.clone(),
+            algorithm,
+            encoding_key,
+            verification_keys,
+        })
+    }
+
+    /// Switches to the keys in `next`, e.g. after the key files changed.
+    /// Keys that are not in `next` keep verifying tokens, and stay in the
+    /// JWKS, for `token_secs` longer so the tokens they signed run out
+    /// first. Keys retired earlier whose time is up are dropped.
+    pub fn replace(&mut self, next: SigningKeys, now: u64, token_secs: u64) {
+        let current = std::mem::replace(self, next);
+        let retired: Vec<_> = current
+            .verification_keys
+            .into_iter()
+            .filter_map(|mut key| {
+                if self
+                    .verification_keys
+                    .iter()
+                    .any(|configured| configured.kid == key.kid)
+                {
+                    return None;
+                }
+                match key.retired_until {
+                    Some(until) if until <= now => None,
+                    Some(_) => Some(key),
+                    None => {
+                        key.retired_until = Some(now + token_secs);
+                        Some(key)
+                    }
+                }
+            })
+            .collect();
+        self.verification_keys.extend(retired);
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        encode(&header, claims, &self.encoding_key).expect("Failed to sign token")
+    }
+
+    /// The claims of a token signed by one of our keys, if it is valid.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let header = decode_header(token).ok()?;
+        let key = self
+            .verification_keys
+            .iter()
+            .find(|key| key.kid == header.kid)?;
+        decode::<T>(token, &key.key, &Validation::new(key.algorithm))
+            .map(|data| data.claims)
+            .ok()
+    }
+
+    /// Our public keys as a JSON Web Key Set.
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": self
+                .verification_keys
+                .iter()
+                .filter_map(|key| key.jwk.clone())
+                .collect::<Vec<_>>()
+        })
+    }
+}
+
+enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ed25519 { x: Vec<u8> },
+}
+
+impl PublicKey {
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            PublicKey::Rsa { .. } => Algorithm::RS256,
+            PublicKey::Ed25519 { .. } => Algorithm::EdDSA,
+        }
+    }
+
+    fn decoding_key(&self) -> jsonwebtoken::errors::Result<DecodingKey> {
+        match self {
+            PublicKey::Rsa { n, e } => DecodingKey::from_rsa_components(
+                &URL_SAFE_NO_PAD.encode(n),
+                &URL_SAFE_NO_PAD.encode(e),
+            ),
+            PublicKey::Ed25519 { x } => DecodingKey::from_ed_components(&URL_SAFE_NO_PAD.encode(x)),
+        }
+    }
+}
+
+struct LoadedKey {
+    public: PublicKey,
+    /// Present when the file held a private key.
+    encoding_key: Option<EncodingKey>,
+}
+
+fn load_pem(path: &Path, contents: &[u8]) -> Result<LoadedKey, KeyError> {
+    let invalid = |message: String| KeyError::Invalid(path.to_path_buf(), message);
+    let pem = pem::parse(contents).map_err(|e| invalid(e.to_string()))?;
+    let der = pem.contents();
+
+    match pem.tag() {
+        "PRIVATE KEY" => {
+            if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+                return Ok(LoadedKey {
+                    public: PublicKey::Ed25519 {
+                        x: pair.public_key().as_ref().to_vec(),
+                    },
+                    encoding_key: Some(
+                        EncodingKey::from_ed_pem(contents).map_err(|e| invalid(e.to_string()))?,
+                    ),
+                });
+            }
+            let pair = RsaKeyPair::from_pkcs8(der).map_err(|e| invalid(e.to_string()))?;
+            rsa_private_key(&pair, contents).map_err(invalid)
+        }
+        "RSA PRIVATE KEY" => {
+            let pair = RsaKeyPair::from_der(der).map_err(|e| invalid(e.to_string()))?;
+            rsa_private_key(&pair, contents).map_err(invalid)
+        }
+        "PUBLIC KEY" => Ok(LoadedKey {
+            public: parse_public_key(der).map_err(invalid)?,
+            encoding_key: None,
+        }),
+        "RSA PUBLIC KEY" => Ok(LoadedKey {
+            public: parse_rsa_public_key(der).map_err(invalid)?,
+            encoding_key: None,
+        }),
+        tag => Err(invalid(format!("unsupported PEM section {}", tag))),
+    }
+}
+
+fn rsa_private_key(pair: &RsaKeyPair, contents: &[u8]) -> Result<LoadedKey, String> {
+    let components: RsaPublicKeyComponents<Vec<u8>> = pair.public().into();
+    Ok(LoadedKey {
+        public: PublicKey::Rsa {
+            n: components.n,
+            e: components.e,
+        },
+        encoding_key: Some(EncodingKey::from_rsa_pem(contents).map_err(|e| e.to_string())?),
+    })
+}
+
+/// Reads an RSA or Ed25519 `SubjectPublicKeyInfo`.
+fn parse_public_key(der: &[u8]) -> Result<PublicKey, String> {
+    let blocks = simple_asn1::from_der(der).map_err(|e| e.to_string())?;
+    let [ASN1Block::Sequence(_, items)] = blocks.as_slice() else {
+        return Err("not a public key".to_string());
+    };
+    let [ASN1Block::Sequence(_, algorithm), ASN1Block::BitString(_, _, key)] = items.as_slice()
+    else {
+        return Err("not a public key".to_string());
+    };
+    match algorithm.first() {
+        Some(ASN1Block::ObjectIdentifier(_, id)) if *id == oid!(1, 2, 840, 113549, 1, 1, 1) => {
+            parse_rsa_public_key(key)
+        }
+        Some(ASN1Block::ObjectIdentifier(_, id)) if *id == oid!(1, 3, 101, 112) => {
+            Ok(PublicKey::Ed25519 { x: key.clone() })
+        }
+        _ => Err("only RSA and Ed25519 keys are supported".to_string()),
+    }
+}
+
+/// Reads a PKCS#1 `RSAPublicKey`.
+fn parse_rsa_public_key(der: &[u8]) -> Result<PublicKey, String> {
+    let blocks = simple_asn1::from_der(der).map_err(|e| e.to_string())?;
+    let [ASN1Block::Sequence(_, items)] = blocks.as_slice() else {
+        return Err("not an RSA public key".to_string());
+    };
+    let [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] = items.as_slice() else {
+        return Err("not an RSA public key".to_string());
+    };
+    Ok(PublicKey::Rsa {
+        n: n.to_bytes_be().1,
+        e: e.to_bytes_be().1,
+    })
+}
+
+fn jwk(kid: &str, public: &PublicKey) -> Value {
+    match public {
+        PublicKey::Rsa { n, e } => json!({
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": kid,
+            "n": URL_SAFE_NO_PAD.encode(n),
+            "e": URL_SAFE_NO_PAD.encode(e),
+        }),
+        PublicKey::Ed25519 { x } => json!({
+            "kty": "OKP",
+            "use": "sig",
+            "alg": "EdDSA",
+            "kid": kid,
+            "crv": "Ed25519",
+            "x": URL_SAFE_NO_PAD.encode(x),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use std::fs;
+
+    fn ed25519_key_file(name: &str) -> PathBuf {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let path = std::env::temp_dir().join(format!("{}.{}.pem", name, std::process::id()));
+        fs::write(
+            &path,
+            pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref().to_vec())),
+        )
+        .unwrap();
+        path
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "alice", "exp": 4_000_000_000u64 })
+    }
+
+    fn kids(keys: &SigningKeys) -> Vec<String> {
+        keys.jwks()["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["kid"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn a_replaced_key_verifies_until_its_tokens_expire() {
+        let old = ed25519_key_file("old");
+        let new = ed25519_key_file("new");
+        let mut keys = SigningKeys::from_pem_files(std::slice::from_ref(&old)).unwrap();
+        let old_token = keys.sign(&claims());
+
+        keys.replace(
+            SigningKeys::from_pem_files(std::slice::from_ref(&new)).unwrap(),
+            100,
+            60,
+        );
+        let new_token = keys.sign(&claims());
+        assert_eq!(
+            decode_header(&new_token).unwrap().kid.as_deref(),
+            Some("new")
+        );
+        assert!(keys.verify::<Value>(&old_token).is_some());
+        assert!(keys.verify::<Value>(&new_token).is_some());
+        assert_eq!(kids(&keys), ["new", "old"]);
+
+        // Reloading the same files before then keeps the old key...
+        keys.replace(
+            SigningKeys::from_pem_files(std::slice::from_ref(&new)).unwrap(),
+            159,
+            60,
+        );
+        assert!(keys.verify::<Value>(&old_token).is_some());
+        // ...and after, drops it.
+        keys.replace(
+            SigningKeys::from_pem_files(std::slice::from_ref(&new)).unwrap(),
+            160,
+            60,
+        );
+        assert!(keys.verify::<Value>(&old_token).is_none());
+        assert_eq!(kids(&keys), ["new"]);
+
+        let _ = fs::remove_file(old);
+        let _ = fs::remove_file(new);
+    }
+
+    #[test]
+    fn a_key_kept_in_the_list_is_not_retired() {
+        let first = ed25519_key_file("first");
+        let second = ed25519_key_file("second");
+        let mut keys = SigningKeys::from_pem_files(std::slice::from_ref(&first)).unwrap();
+        let token = keys.sign(&claims());
+
+        // Rotating signs with the new key but still lists the old one.
+        keys.replace(
+            SigningKeys::from_pem_files(&[second.clone(), first.clone()]).unwrap(),
+            100,
+            60,
+        );
+        keys.replace(
+            SigningKeys::from_pem_files(&[second.clone(), first.clone()]).unwrap(),
+            1000,
+            60,
+        );
+        assert!(keys.verify::<Value>(&token).is_some());
+        assert_eq!(kids(&keys), ["second", "first"]);
+
+        let _ = fs::remove_file(first);
+        let _ = fs::remove_file(second);
+    }
+
+    #[test]
+    fn tokens_signed_with_a_secret_survive_the_switch_to_key_files() {
+        let path = ed25519_key_file("after-secret");
+        let mut keys = SigningKeys::hmac(b"old secret");
+        let old_token = keys.sign(&claims());
+
+        keys.replace(
+            SigningKeys::from_pem_files(std::slice::from_ref(&path)).unwrap(),
+            100,
+            60,
+        );
+        assert!(keys.verify::<Value>(&old_token).is_some());
+        // The secret is never published.
+        assert_eq!(kids(&keys), ["after-secret"]);
+        let new_token = keys.sign(&claims());
+        assert_eq!(decode_header(&new_token).unwrap().alg, Algorithm::EdDSA);
+
+        keys.replace(
+            SigningKeys::from_pem_files(std::slice::from_ref(&path)).unwrap(),
+            160,
+            60,
+        );
+        assert!(keys.verify::<Value>(&old_token).is_none());
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::models::book::{Book, Page};
+use handlebars::Handlebars;
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The stylesheet the exported pages link to, copied next to them.
+const STYLESHEET: &str = "static/style.css";
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(String),
+    Render(String),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(message) => write!(f, "could not write the export: {}", message),
+            ExportError::Render(message) => write!(f, "failed to render template: {}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(e: std::io::Error) -> Self {
+        ExportError::Io(e.to_string())
+    }
+}
+
+impl From<handlebars::RenderError> for ExportError {
+    fn from(e: handlebars::RenderError) -> Self {
+        ExportError::Render(e.to_string())
+    }
+}
+
+/// The pages a reader can get to from the starting page, in the order they
+/// are first reached.
+pub fn reachable_pages(book: &Book) -> Vec<&Page> {
+    let mut seen = HashSet::from([book.starting_page]);
+    let mut queue = VecDeque::from([book.starting_page]);
+    let mut pages = Vec::new();
+    while let Some(page_id) = queue.pop_front() {
+        let Some(page) = book.pages.iter().find(|page| page.id == page_id) else {
+            continue;
+        };
+        for choice in &page.choices {
+            if seen.insert(choice.target_page_id) {
+                queue.push_back(choice.target_page_id);
+            }
+        }
+        pages.push(page);
+    }
+    pages
+}
+
+/// Writes `book` to `out_dir` as a static site that needs no server: the
+/// starting page as `index.html`, every other page reachable from it as
+/// `page-<id>.html` and a copy of the stylesheet. Pages are rendered with
+/// the same templates as the app, with each choice a relative link to its
+/// target page. A static site keeps no playthrough, so choices are always
+/// offered and their conditions and effects are left out. Choices whose
+/// target page does not exist are dropped with a warning. Returns the
+/// number of pages written.
+pub fn export_site(
+    handlebars: &Handlebars,
+    book: &Book,
+    out_dir: &Path,
+) -> Result<usize, ExportError> {
+    fs::create_dir_all(out_dir)?;
+    fs::copy(STYLESHEET, out_dir.join("style.css"))?;
+
+    let pages = reachable_pages(book);
+    let page_ids: HashSet<u32> = pages.iter().map(|page| page.id).collect();
+    for page in &pages {
+        let choices: Vec<_> = page
+            .choices
+            .iter()
+            .filter(|choice| {
+                let exists = page_ids.contains(&choice.target_page_id);
+                if !exists {
+                    log::warn!(
+                        "Leaving out choice \"{}\" on page {} of {}: page {} does not exist",
+                        choice.text,
+                        page.id,
+                        book.title,
+                        choice.target_page_id
+                    );
+                }
+                exists
+            })
+            .map(|choice| {
+                json!({
+                    "text": choice.text,
+                    "href": page_file(book, choice.target_page_id),
+                    "available": true
+                })
+            })
+            .collect();
+        let content = handlebars.render(
+            "book/book_page",
+            &json!({
+                "title": book.title,
+                "book_id": book.id,
+                "export": true,
+                "start_href": page_file(book, book.starting_page),
+                "page": {
+                    "id": page.id,
+                    "content": page.content,
+                    "ending": page.ending,
+                    "choices": choices
+                }
+            }),
+        )?;
+        let html = handlebars.render(
+            "layout",
+            &json!({
+                "title": book.title,
+                "heading": book.title,
+                "export": true,
+                "start_href": page_file(book, book.starting_page),
+                "main_content": content
+            }),
+        )?;
+        fs::write(out_dir.join(page_file(book, page.id)), html)?;
+    }
+    Ok(pages.len())
+}
+
+fn page_file(book: &Book, page_id: u32) -> String {
+    if page_id == book.starting_page {
+        "index.html".to_string()
+    } else {
+        format!("page-{}.html", page_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book() -> Book {
+        serde_json::from_value(json!({
+            "id": 1,
+            "title": "The Cellar",
+            "summary": "",
+            "starting_page": 1,
+            "pages": [
+                { "id": 1, "content": "A cellar door.", "choices": [
+                    { "text": "Go down", "target_page_id": 2 },
+                    { "text": "Fall through the floor", "target_page_id": 9 }
+                ] },
+                { "id": 2, "content": "Darkness.", "ending": { "kind": "neutral", "title": "Lost" } },
+                { "id": 3, "content": "Nobody gets here." }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn exports_reachable_pages_with_relative_links() {
+        let mut handlebars = Handlebars::new();
+        crate::templates::register_templates(&mut handlebars);
+        let out_dir =
+            std::env::temp_dir().join(format!("mustachestory-site-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&out_dir);
+
+        assert_eq!(export_site(&handlebars, &book(), &out_dir).unwrap(), 2);
+        let index = fs::read_to_string(out_dir.join("index.html")).unwrap();
+        assert!(index.contains(r#"href="page-2.html""#));
+        assert!(index.contains("Go down"));
+        assert!(!index.contains("Fall through the floor"));
+        assert!(!index.contains("onclick"));
+        let ending = fs::read_to_string(out_dir.join("page-2.html")).unwrap();
+        assert!(ending.contains(r#"href="index.html""#));
+        assert!(!out_dir.join("page-3.html").exists());
+        assert!(out_dir.join("style.css").exists());
+
+        let _ = fs::remove_dir_all(&out_dir);
+    }
+}
@@ -0,0 +1,643 @@
+use crate::models::book::{Book, Choice, Ending, EndingKind, Page, UnmetChoice};
+use crate::models::progress::PlaythroughState;
+use crate::services::book_loader::{id_from_name, tidy_content, ImportWarning};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A story read from Twine, with whatever could not be carried over.
+#[derive(Debug)]
+pub struct TwineImport {
+    pub book: Book,
+    pub warnings: Vec<ImportWarning>,
+}
+
+#[derive(Debug)]
+pub struct TwineError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl TwineError {
+    fn new(line: Option<usize>, message: impl Into<String>) -> Self {
+        TwineError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TwineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The Twee 3 `StoryData` passage.
+#[derive(Deserialize)]
+struct StoryData {
+    ifid: Option<String>,
+    start: Option<String>,
+}
+
+struct Passage {
+    /// The page id, taken from the `pid` in HTML archives.
+    pid: Option<u32>,
+    name: String,
+    tags: Vec<String>,
+    text: String,
+    /// Line of the passage's first line of text in the source file.
+    line: usize,
+}
+
+enum Start {
+    Name(String),
+    Pid(u32),
+}
+
+struct Story {
+    title: String,
+    ifid: Option<String>,
+    start: Option<Start>,
+    passages: Vec<Passage>,
+}
+
+/// Reads Twee 3 source, as exported by Twine or written by hand.
+pub fn parse_twee(source: &str) -> Result<TwineImport, TwineError> {
+    let mut passages = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        if let Some(header) = line.strip_prefix("::") {
+            let (name, tags) = parse_passage_header(header)
+                .ok_or_else(|| TwineError::new(Some(index + 1), "passage has no name"))?;
+            passages.push(Passage {
+                pid: None,
+                name,
+                tags,
+                text: String::new(),
+                line: index + 2,
+            });
+        } else if let Some(passage) = passages.last_mut() {
+            passage.text.push_str(line);
+            passage.text.push('\n');
+        } else if !line.trim().is_empty() {
+            return Err(TwineError::new(
+                Some(index + 1),
+                "text before the first passage",
+            ));
+        }
+    }
+
+    let mut title = None;
+    let mut data = None;
+    let mut story_passages = Vec::new();
+    for passage in passages {
+        match passage.name.as_str() {
+            "StoryTitle" => title = Some(passage.text.trim().to_string()),
+            "StoryData" => {
+                let story_data: StoryData = serde_json::from_str(&passage.text).map_err(|e| {
+                    TwineError::new(Some(passage.line + e.line() - 1), e.to_string())
+                })?;
+                data = Some(story_data);
+            }
+            _ => story_passages.push(passage),
+        }
+    }
+
+    let title = title.ok_or_else(|| TwineError::new(None, "missing StoryTitle passage"))?;
+    let (ifid, start) = match data {
+        Some(data) => (data.ifid, data.start.map(Start::Name)),
+        None => (None, None),
+    };
+    build_book(Story {
+        title,
+        ifid,
+        start,
+        passages: story_passages,
+    })
+}
+
+/// Reads every story in a Twine 2 HTML file, either a single published story
+/// or a library archive.
+pub fn parse_twine_html(source: &str) -> Result<Vec<TwineImport>, TwineError> {
+    let mut imports = Vec::new();
+    let mut rest = 0;
+    while let Some(found) = source[rest..].find("<tw-storydata") {
+        let start = rest + found;
+        let line = line_at(source, start);
+        let (attributes, body_start) = open_tag(source, start)
+            .ok_or_else(|| TwineError::new(Some(line), "unclosed <tw-storydata> tag"))?;
+        let end = source[body_start..]
+            .find("</tw-storydata>")
+            .map(|end| body_start + end)
+            .ok_or_else(|| TwineError::new(Some(line), "missing </tw-storydata>"))?;
+
+        let title = attribute(attributes, "name")
+            .ok_or_else(|| TwineError::new(Some(line), "story has no name"))?;
+        let start_pid = attribute(attributes, "startnode")
+            .map(|pid| parse_pid(&pid, line))
+            .transpose()?;
+        let passages = parse_passage_elements(source, body_start, end)?;
+        imports.push(build_book(Story {
+            title,
+            ifid: attribute(attributes, "ifid"),
+            start: start_pid.map(Start::Pid),
+            passages,
+        })?);
+        rest = end;
+    }
+
+    if imports.is_empty() {
+        return Err(TwineError::new(None, "no Twine 2 story data found"));
+    }
+    Ok(imports)
+}
+
+fn parse_passage_elements(
+    source: &str,
+    from: usize,
+    to: usize,
+) -> Result<Vec<Passage>, TwineError> {
+    let mut passages = Vec::new();
+    let mut rest = from;
+    while let Some(found) = source[rest..to].find("<tw-passagedata") {
+        let start = rest + found;
+        let line = line_at(source, start);
+        let (attributes, text_start) = open_tag(source, start)
+            .ok_or_else(|| TwineError::new(Some(line), "unclosed <tw-passagedata> tag"))?;
+        let text_end = source[text_start..to]
+            .find("</tw-passagedata>")
+            .map(|end| text_start + end)
+            .ok_or_else(|| TwineError::new(Some(line), "missing </tw-passagedata>"))?;
+
+        let pid = attribute(attributes, "pid")
+            .ok_or_else(|| TwineError::new(Some(line), "passage has no pid"))?;
+        passages.push(Passage {
+            pid: Some(parse_pid(&pid, line)?),
+            name: attribute(attributes, "name")
+                .ok_or_else(|| TwineError::new(Some(line), "passage has no name"))?,
+            tags: attribute(attributes, "tags")
+                .map(|tags| tags.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            text: unescape_html(&source[text_start..text_end]),
+            line,
+        });
+        rest = text_end;
+    }
+    Ok(passages)
+}
+
+fn build_book(story: Story) -> Result<TwineImport, TwineError> {
+    let mut warnings = Vec::new();
+    let mut passages = Vec::new();
+    for passage in story.passages {
+        if let Some(tag) = passage
+            .tags
+            .iter()
+            .find(|tag| *tag == "script" || *tag == "stylesheet")
+        {
+            warnings.push(ImportWarning {
+                line: Some(passage.line),
+                passage: passage.name.clone(),
+                message: format!("{} passage ignored", tag),
+            });
+        } else {
+            passages.push(passage);
+        }
+    }
+
+    let mut ids = HashMap::new();
+    let mut names = HashMap::new();
+    for (index, passage) in passages.iter().enumerate() {
+        let id = passage.pid.unwrap_or(index as u32 + 1);
+        if ids.insert(passage.name.as_str(), id).is_some() {
+            return Err(TwineError::new(
+                Some(passage.line),
+                format!("passage \"{}\" is defined more than once", passage.name),
+            ));
+        }
+        if let Some(other) = names.insert(id, passage.name.as_str()) {
+            return Err(TwineError::new(
+                Some(passage.line),
+                format!(
+                    "passages \"{}\" and \"{}\" have the same pid {}",
+                    other, passage.name, id
+                ),
+            ));
+        }
+    }
+
+    let starting_page = match &story.start {
+        Some(Start::Pid(pid)) => *pid,
+        Some(Start::Name(name)) => *ids.get(name.as_str()).ok_or_else(|| {
+            TwineError::new(None, format!("start passage \"{}\" does not exist", name))
+        })?,
+        None => passages
+            .iter()
+            .find(|passage| passage.name == "Start")
+            .or(passages.first())
+            .map(|passage| ids[passage.name.as_str()])
+            .ok_or_else(|| TwineError::new(None, "story has no passages"))?,
+    };
+
+    let pages = passages
+        .iter()
+        .map(|passage| {
+            let (content, links) = parse_passage_text(passage, &mut warnings);
+            let choices = links
+                .into_iter()
+                .filter_map(|link| match ids.get(link.target.as_str()) {
+                    Some(&target_page_id) => Some(Choice {
+                        text: link.text,
+                        target_page_id,
+                        conditions: Vec::new(),
+                        effects: Vec::new(),
+                        when_unmet: UnmetChoice::default(),
+                    }),
+                    None => {
+                        warnings.push(ImportWarning {
+                            line: Some(link.line),
+                            passage: passage.name.clone(),
+                            message: format!("link to missing passage \"{}\"", link.target),
+                        });
+                        None
+                    }
+                })
+                .collect();
+            Page {
+                id: ids[passage.name.as_str()],
+                content,
+                choices,
+                ending: ending(passage),
+            }
+        })
+        .collect();
+
+    Ok(TwineImport {
+        book: Book {
+            id: book_id(story.ifid.as_deref(), &story.title),
+            title: story.title,
+            summary: String::new(),
+            pages,
+            starting_page,
+            initial_state: PlaythroughState::default(),
+        },
+        warnings,
+    })
+}
+
+/// Passages tagged `ending`, or with an ending kind such as `victory` or
+/// `death`, are endings titled after the passage.
+fn ending(passage: &Passage) -> Option<Ending> {
+    let kind = passage
+        .tags
+        .iter()
+        .find_map(|tag| EndingKind::from_tag(tag))?;
+    Some(Ending {
+        kind,
+        title: passage.name.clone(),
+    })
+}
+
+/// Books are numbered from the first eight hex digits of the story's IFID,
+/// so the id stays the same across exports, or from its title when there is
+/// no IFID.
+fn book_id(ifid: Option<&str>, title: &str) -> u32 {
+    ifid.and_then(|ifid| ifid.get(..8))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .unwrap_or_else(|| id_from_name(title))
+}
+
+struct Link {
+    text: String,
+    target: String,
+    line: usize,
+}
+
+/// Splits a passage into its prose and its links. Links become choices and
+/// macros are dropped, since neither can be shown as page text.
+fn parse_passage_text(passage: &Passage, warnings: &mut Vec<ImportWarning>) -> (String, Vec<Link>) {
+    let text = passage.text.as_str();
+    let mut content = String::new();
+    let mut links = Vec::new();
+    let mut rest = 0;
+    let mut line = passage.line;
+
+    while rest < text.len() {
+        let tail = &text[rest..];
+        let skipped = if let Some(inner) = tail
+            .strip_prefix("[[")
+            .and_then(|t| t.find("]]").map(|end| &t[..end]))
+        {
+            let (link_text, target) = split_link(inner);
+            links.push(Link {
+                text: link_text.trim().to_string(),
+                target: target.trim().to_string(),
+                line,
+            });
+            &tail[..inner.len() + 4]
+        } else if let Some(macro_len) = macro_at(tail) {
+            warnings.push(ImportWarning {
+                line: Some(line),
+                passage: passage.name.clone(),
+                message: format!("unsupported macro {}", &tail[..macro_len]),
+            });
+            &tail[..macro_len]
+        } else {
+            let c = tail.chars().next().unwrap();
+            content.push(c);
+            &tail[..c.len_utf8()]
+        };
+        rest += skipped.len();
+        line += skipped.matches('\n').count();
+    }
+
+    (tidy_content(&content), links)
+}
+
+/// The text and target of a link, from `[[Target]]`, `[[Text|Target]]`,
+/// `[[Text->Target]]` or `[[Target<-Text]]`. SugarCube setters after `][`
+/// are dropped.
+fn split_link(inner: &str) -> (&str, &str) {
+    let inner = inner.split("][").next().unwrap_or(inner);
+    if let Some((text, target)) = inner.split_once('|') {
+        (text, target)
+    } else if let Some((text, target)) = inner.rsplit_once("->") {
+        (text, target)
+    } else if let Some((target, text)) = inner.split_once("<-") {
+        (text, target)
+    } else {
+        (inner, inner)
+    }
+}
+
+/// The length of a SugarCube `<<macro>>` or Harlowe `(macro: ...)` call at
+/// the start of `text`.
+fn macro_at(text: &str) -> Option<usize> {
+    if text.starts_with("<<") {
+        return text.find(">>").map(|end| end + 2);
+    }
+    let name = text.strip_prefix('(')?;
+    let name_len = name
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .filter(|&len| len > 0)?;
+    if !name[name_len..].starts_with(':') {
+        return None;
+    }
+    let mut depth = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The name and tags from a Twee header such as
+/// `Name [tag1 tag2] {"position":"100,100"}`. The metadata is ignored.
+fn parse_passage_header(header: &str) -> Option<(String, Vec<String>)> {
+    let mut name = String::new();
+    let mut chars = header.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '\\' => {
+                chars.next();
+                if let Some(escaped) = chars.next() {
+                    name.push(escaped);
+                }
+            }
+            '[' | '{' => break,
+            _ => {
+                name.push(c);
+                chars.next();
+            }
+        }
+    }
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return None;
+    }
+
+    let rest: String = chars.collect();
+    let tags = rest
+        .trim_start()
+        .strip_prefix('[')
+        .and_then(|tags| tags.split_once(']'))
+        .map(|(tags, _)| tags.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+    Some((name, tags))
+}
+
+fn parse_pid(pid: &str, line: usize) -> Result<u32, TwineError> {
+    pid.parse()
+        .map_err(|_| TwineError::new(Some(line), format!("pid \"{}\" is not a number", pid)))
+}
+
+/// The attributes of the tag opening at `start`, and where its content
+/// begins.
+fn open_tag(source: &str, start: usize) -> Option<(&str, usize)> {
+    let end = start + source[start..].find('>')?;
+    Some((&source[start..end], end + 1))
+}
+
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let pattern = format!(" {}=\"", name);
+    let start = attributes.find(&pattern)? + pattern.len();
+    let end = start + attributes[start..].find('"')?;
+    Some(unescape_html(&attributes[start..end]))
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn choices(page: &Page) -> Vec<(&str, u32)> {
+        page.choices
+            .iter()
+            .map(|choice| (choice.text.as_str(), choice.target_page_id))
+            .collect()
+    }
+
+    #[test]
+    fn splits_every_link_syntax() {
+        assert_eq!(split_link("Cellar"), ("Cellar", "Cellar"));
+        assert_eq!(split_link("Go down|Cellar"), ("Go down", "Cellar"));
+        assert_eq!(split_link("Go down->Cellar"), ("Go down", "Cellar"));
+        assert_eq!(split_link("Cellar<-Go down"), ("Go down", "Cellar"));
+        assert_eq!(split_link("A->B->Cellar"), ("A->B", "Cellar"));
+        assert_eq!(
+            split_link("Go down|Cellar][$lamp to true"),
+            ("Go down", "Cellar")
+        );
+    }
+
+    #[test]
+    fn finds_sugarcube_and_harlowe_macros() {
+        assert_eq!(macro_at("<<set $lamp to true>> rest"), Some(21));
+        assert_eq!(macro_at("<<unclosed"), None);
+        assert_eq!(macro_at("(set: $gold to (it + 1)) rest"), Some(24));
+        assert_eq!(macro_at("(if-else: true)"), Some(15));
+        assert_eq!(macro_at("(an aside) rest"), None);
+        assert_eq!(macro_at("(: no name)"), None);
+        assert_eq!(macro_at("plain text"), None);
+    }
+
+    #[test]
+    fn parses_passage_headers() {
+        assert_eq!(
+            parse_passage_header(r#" Start [intro  victory] {"position":"100,100"}"#),
+            Some((
+                "Start".to_string(),
+                vec!["intro".to_string(), "victory".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse_passage_header(r"A \[bracketed\] name"),
+            Some(("A [bracketed] name".to_string(), Vec::new()))
+        );
+        assert_eq!(
+            parse_passage_header(r#"Cellar {"size":"200,100"}"#),
+            Some(("Cellar".to_string(), Vec::new()))
+        );
+        assert_eq!(parse_passage_header("  [tag]"), None);
+    }
+
+    #[test]
+    fn unescapes_each_entity_once() {
+        assert_eq!(
+            unescape_html("&lt;b&gt; &quot;Tom&#39;s&quot; &amp; Jerry&apos;s &amp;lt;"),
+            "<b> \"Tom's\" & Jerry's &lt;"
+        );
+    }
+
+    #[test]
+    fn imports_twee_with_links_endings_and_dropped_macros() {
+        let source = r#":: StoryTitle
+The Cellar
+
+:: StoryData
+{"ifid": "0A1B2C3D-0000-0000-0000-000000000000", "start": "Door"}
+
+:: Door
+A cellar door. <<set $seen to true>>
+[[Open it->Stairs]]
+[[Leave|Home]]
+[[Nowhere]]
+
+:: Stairs
+(set: $lamp to (random: 1, 2))Dark stairs.
+[[Door<-Back]]
+
+:: Home [victory]
+You go home.
+
+:: Styles [stylesheet]
+body { color: red; }
+"#;
+        let import = parse_twee(source).unwrap();
+        let book = &import.book;
+        assert_eq!(book.id, 0x0A1B2C3D);
+        assert_eq!(book.title, "The Cellar");
+        assert_eq!(book.starting_page, 1);
+        assert_eq!(book.pages.len(), 3);
+        assert_eq!(book.pages[0].content, "A cellar door.");
+        assert_eq!(choices(&book.pages[0]), [("Open it", 2), ("Leave", 3)]);
+        assert_eq!(book.pages[1].content, "Dark stairs.");
+        assert_eq!(choices(&book.pages[1]), [("Back", 1)]);
+        let ending = book.pages[2].ending.as_ref().unwrap();
+        assert_eq!(ending.kind, EndingKind::Victory);
+        assert_eq!(ending.title, "Home");
+
+        let warnings: Vec<_> = import
+            .warnings
+            .iter()
+            .map(|warning| (warning.line, warning.message.as_str()))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                (Some(21), "stylesheet passage ignored"),
+                (Some(8), "unsupported macro <<set $seen to true>>"),
+                (Some(11), "link to missing passage \"Nowhere\""),
+                (Some(14), "unsupported macro (set: $lamp to (random: 1, 2))"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_twee_errors_with_their_line() {
+        let error = parse_twee("Hello\n:: Start\n").unwrap_err();
+        assert_eq!(error.line, Some(1));
+        let error = parse_twee(":: StoryTitle\nT\n:: Start\nA\n:: Start\nB\n").unwrap_err();
+        assert_eq!(error.line, Some(6));
+        let error = parse_twee(":: StoryTitle\nT\n:: StoryData\n{\n\"start\": 1\n}\n").unwrap_err();
+        assert_eq!(error.line, Some(5));
+    }
+
+    const ARCHIVE: &str = r#"<html><body>
+<tw-storydata name="First &amp; Best" startnode="2" ifid="00000001-AAAA" format="Harlowe">
+<tw-passagedata pid="1" name="End" tags="death">You fall.</tw-passagedata>
+<tw-passagedata pid="2" name="Cliff" tags="">A cliff. [[Jump-&gt;End]]</tw-passagedata>
+</tw-storydata>
+<tw-storydata name="Second" startnode="7" ifid="00000002-BBBB" format="SugarCube">
+<tw-passagedata pid="7" name="Start" tags="">&lt;&lt;set $x to 1&gt;&gt;Hello. [[Bye]]</tw-passagedata>
+<tw-passagedata pid="8" name="Bye" tags="ending">Goodbye.</tw-passagedata>
+</tw-storydata>
+</body></html>
+"#;
+
+    #[test]
+    fn imports_every_story_in_an_archive() {
+        let imports = parse_twine_html(ARCHIVE).unwrap();
+        assert_eq!(imports.len(), 2);
+
+        let first = &imports[0].book;
+        assert_eq!((first.id, first.title.as_str()), (1, "First & Best"));
+        assert_eq!(first.starting_page, 2);
+        assert_eq!(choices(&first.pages[1]), [("Jump", 1)]);
+        assert_eq!(
+            first.pages[0].ending.as_ref().unwrap().kind,
+            EndingKind::Death
+        );
+
+        let second = &imports[1].book;
+        assert_eq!((second.id, second.starting_page), (2, 7));
+        assert_eq!(second.pages[0].content, "Hello.");
+        assert_eq!(choices(&second.pages[0]), [("Bye", 8)]);
+        assert_eq!(imports[1].warnings.len(), 1);
+        assert_eq!(imports[1].warnings[0].line, Some(7));
+    }
+
+    #[test]
+    fn refuses_passages_sharing_a_pid() {
+        let archive = ARCHIVE.replace(r#"pid="8""#, r#"pid="7""#);
+        let error = parse_twine_html(&archive).unwrap_err();
+        assert_eq!(error.line, Some(8));
+        assert!(error.message.contains("same pid 7"), "{}", error.message);
+    }
+
+    #[test]
+    fn refuses_html_without_stories() {
+        assert!(parse_twine_html("<html></html>").is_err());
+    }
+}
@@ -0,0 +1,448 @@
+use crate::models::user::User;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+
+#[derive(Debug)]
+pub enum UserStoreError {
+    UsernameTaken,
+    NotFound,
+    Storage(String),
+}
+
+impl fmt::Display for UserStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserStoreError::UsernameTaken => write!(f, "username is already taken"),
+            UserStoreError::NotFound => write!(f, "user not found"),
+            UserStoreError::Storage(message) => write!(f, "user store error: {}", message),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for UserStoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        UserStoreError::Storage(e.to_string())
+    }
+}
+
+pub trait UserRepository: Send + Sync {
+    fn find(&self, username: &str) -> Result<Option<User>, UserStoreError>;
+    fn list(&self) -> Result<Vec<User>, UserStoreError>;
+    fn insert(&self, user: User) -> Result<(), UserStoreError>;
+    fn update(&self, user: User) -> Result<(), UserStoreError>;
+    fn delete(&self, username: &str) -> Result<(), UserStoreError>;
+    /// The user an external identity (an OpenID Connect issuer and subject)
+    /// has been linked to.
+    fn find_by_identity(&self, issuer: &str, subject: &str)
+        -> Result<Option<User>, UserStoreError>;
+    fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        username: &str,
+    ) -> Result<(), UserStoreError>;
+}
+
+pub struct SqliteUserRepository {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteUserRepository {
+    pub fn open(path: &Path) -> Result<Self, UserStoreError> {
+        let connection = Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL,
+                locked INTEGER NOT NULL DEFAULT 0,
+                role TEXT NOT NULL DEFAULT 'reader'
+            )",
+            [],
+        )?;
+        let has_role: bool = connection.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'role'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_role {
+            connection.execute(
+                "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'reader'",
+                [],
+            )?;
+        }
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS identities (
+                issuer TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                username TEXT NOT NULL,
+                PRIMARY KEY (issuer, subject)
+            )",
+            [],
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl UserRepository for SqliteUserRepository {
+    fn find(&self, username: &str) -> Result<Option<User>, UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let user = connection
+            .query_row(
+                "SELECT username, password_hash, locked, role FROM users WHERE username = ?1",
+                params![username],
+                user_from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    fn list(&self) -> Result<Vec<User>, UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare("SELECT username, password_hash, locked, role FROM users ORDER BY username")?;
+        let users = statement
+            .query_map([], user_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    fn insert(&self, user: User) -> Result<(), UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO users (username, password_hash, locked, role)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                user.username,
+                user.password_hash,
+                user.locked,
+                user.role.as_str()
+            ],
+        )?;
+        if inserted == 0 {
+            return Err(UserStoreError::UsernameTaken);
+        }
+        Ok(())
+    }
+
+    fn update(&self, user: User) -> Result<(), UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE users SET password_hash = ?2, locked = ?3, role = ?4 WHERE username = ?1",
+            params![
+                user.username,
+                user.password_hash,
+                user.locked,
+                user.role.as_str()
+            ],
+        )?;
+        if updated == 0 {
+            return Err(UserStoreError::NotFound);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, username: &str) -> Result<(), UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let deleted =
+            connection.execute("DELETE FROM users WHERE username = ?1", params![username])?;
+        if deleted == 0 {
+            return Err(UserStoreError::NotFound);
+        }
+        connection.execute(
+            "DELETE FROM identities WHERE username = ?1",
+            params![username],
+        )?;
+        Ok(())
+    }
+
+    fn find_by_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<User>, UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        let user = connection
+            .query_row(
+                "SELECT users.username, password_hash, locked, role FROM users
+                 JOIN identities ON identities.username = users.username
+                 WHERE issuer = ?1 AND subject = ?2",
+                params![issuer, subject],
+                user_from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        username: &str,
+    ) -> Result<(), UserStoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO identities (issuer, subject, username) VALUES (?1, ?2, ?3)",
+            params![issuer, subject, username],
+        )?;
+        Ok(())
+    }
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    let role: String = row.get(3)?;
+    Ok(User {
+        username: row.get(0)?,
+        password_hash: row.get(1)?,
+        locked: row.get(2)?,
+        role: role.parse().unwrap_or_default(),
+    })
+}
+
+/// Keeps users in memory only. Useful for tests and throwaway servers.
+#[derive(Default)]
+pub struct InMemoryUserRepository {
+    users: RwLock<HashMap<String, User>>,
+    identities: RwLock<HashMap<(String, String), String>>,
+}
+
+impl InMemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UserRepository for InMemoryUserRepository {
+    fn find(&self, username: &str) -> Result<Option<User>, UserStoreError> {
+        Ok(self.users.read().unwrap().get(username).cloned())
+    }
+
+    fn list(&self) -> Result<Vec<User>, UserStoreError> {
+        let mut users: Vec<User> = self.users.read().unwrap().values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Ok(users)
+    }
+
+    fn insert(&self, user: User) -> Result<(), UserStoreError> {
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(&user.username) {
+            return Err(UserStoreError::UsernameTaken);
+        }
+        users.insert(user.username.clone(), user);
+        Ok(())
+    }
+
+    fn update(&self, user: User) -> Result<(), UserStoreError> {
+        match self.users.write().unwrap().get_mut(&user.username) {
+            Some(existing) => {
+                *existing = user;
+                Ok(())
+            }
+            None => Err(UserStoreError::NotFound),
+        }
+    }
+
+    fn delete(&self, username: &str) -> Result<(), UserStoreError> {
+        match self.users.write().unwrap().remove(username) {
+            Some(_) => {
+                self.identities
+                    .write()
+                    .unwrap()
+                    .retain(|_, linked| linked != username);
+                Ok(())
+            }
+            None => Err(UserStoreError::NotFound),
+        }
+    }
+
+    fn find_by_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+    ) -> Result<Option<User>, UserStoreError> {
+        let identities = self.identities.read().unwrap();
+        match identities.get(&(issuer.to_string(), subject.to_string())) {
+            Some(username) => self.find(username),
+            None => Ok(None),
+        }
+    }
+
+    fn link_identity(
+        &self,
+        issuer: &str,
+        subject: &str,
+        username: &str,
+    ) -> Result<(), UserStoreError> {
+        self.identities.write().unwrap().insert(
+            (issuer.to_string(), subject.to_string()),
+            username.to_string(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::user::Role;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mustachestory-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn user(username: &str, role: Role) -> User {
+        User {
+            username: username.to_string(),
+            password_hash: format!("hash of {}", username),
+            locked: false,
+            role,
+        }
+    }
+
+    fn names(users: Vec<User>) -> Vec<String> {
+        users.into_iter().map(|user| user.username).collect()
+    }
+
+    #[test]
+    fn finds_inserted_users_after_reopening() {
+        let path = temp_path("users-insert");
+        let users = SqliteUserRepository::open(&path).unwrap();
+        users.insert(user("bob", Role::Reader)).unwrap();
+        let mut alice = user("alice", Role::Editor);
+        alice.locked = true;
+        users.insert(alice).unwrap();
+        drop(users);
+
+        let users = SqliteUserRepository::open(&path).unwrap();
+        let alice = users.find("alice").unwrap().unwrap();
+        assert_eq!(alice.password_hash, "hash of alice");
+        assert!(alice.locked);
+        assert_eq!(alice.role, Role::Editor);
+        assert!(users.find("carol").unwrap().is_none());
+        assert_eq!(names(users.list().unwrap()), ["alice", "bob"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_second_insert_of_a_username_is_refused() {
+        let path = temp_path("users-duplicate");
+        let users = SqliteUserRepository::open(&path).unwrap();
+        users.insert(user("alice", Role::Reader)).unwrap();
+
+        let mut again = user("alice", Role::Admin);
+        again.password_hash = "another hash".to_string();
+        assert!(matches!(
+            users.insert(again),
+            Err(UserStoreError::UsernameTaken)
+        ));
+        let alice = users.find("alice").unwrap().unwrap();
+        assert_eq!(alice.password_hash, "hash of alice");
+        assert_eq!(alice.role, Role::Reader);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn updates_users_and_refuses_missing_ones() {
+        let path = temp_path("users-update");
+        let users = SqliteUserRepository::open(&path).unwrap();
+        users.insert(user("alice", Role::Reader)).unwrap();
+
+        let mut alice = user("alice", Role::Author);
+        alice.password_hash = "new hash".to_string();
+        users.update(alice).unwrap();
+        let alice = users.find("alice").unwrap().unwrap();
+        assert_eq!(
+            (alice.password_hash.as_str(), alice.role),
+            ("new hash", Role::Author)
+        );
+
+        assert!(matches!(
+            users.update(user("nobody", Role::Reader)),
+            Err(UserStoreError::NotFound)
+        ));
+        assert!(matches!(
+            users.delete("nobody"),
+            Err(UserStoreError::NotFound)
+        ));
+        assert!(users.find("nobody").unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn deleting_a_user_unlinks_their_identities() {
+        let path = temp_path("users-identities");
+        let users = SqliteUserRepository::open(&path).unwrap();
+        users.insert(user("alice", Role::Reader)).unwrap();
+        users.insert(user("bob", Role::Reader)).unwrap();
+        users.link_identity("https://idp", "a-1", "alice").unwrap();
+        users.link_identity("https://idp", "b-1", "bob").unwrap();
+        assert_eq!(
+            users
+                .find_by_identity("https://idp", "a-1")
+                .unwrap()
+                .unwrap()
+                .username,
+            "alice"
+        );
+
+        users.delete("alice").unwrap();
+        assert!(users.find("alice").unwrap().is_none());
+        // A new account with the same name does not inherit the identity.
+        users.insert(user("alice", Role::Reader)).unwrap();
+        assert!(users
+            .find_by_identity("https://idp", "a-1")
+            .unwrap()
+            .is_none());
+        assert!(users
+            .find_by_identity("https://idp", "b-1")
+            .unwrap()
+            .is_some());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn opens_a_database_from_before_roles() {
+        let path = temp_path("users-migrate");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute(
+                "CREATE TABLE users (
+                    username TEXT PRIMARY KEY,
+                    password_hash TEXT NOT NULL,
+                    locked INTEGER NOT NULL DEFAULT 0
+                )",
+                [],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO users (username, password_hash, locked) VALUES ('alice', 'old hash', 1)",
+                [],
+            )
+            .unwrap();
+        drop(connection);
+
+        let users = SqliteUserRepository::open(&path).unwrap();
+        let alice = users.find("alice").unwrap().unwrap();
+        assert_eq!(alice.password_hash, "old hash");
+        assert!(alice.locked);
+        assert_eq!(alice.role, Role::Reader);
+        users.insert(user("bob", Role::Admin)).unwrap();
+        assert_eq!(users.find("bob").unwrap().unwrap().role, Role::Admin);
+        drop(users);
+
+        // Opening it again does not try to add the column twice.
+        let users = SqliteUserRepository::open(&path).unwrap();
+        assert_eq!(names(users.list().unwrap()), ["alice", "bob"]);
+        fs::remove_file(path).unwrap();
+    }
+}
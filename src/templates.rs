@@ -0,0 +1,116 @@
+use handlebars::{handlebars_helper, Handlebars};
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+/// A template found under `src/pages` or `src/components` by the build
+/// script, named after its path there, as in `book/book_page`.
+pub struct TemplateFile {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub source: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+
+/// Registers every template. Each one can also be used as a partial by its
+/// name, as in `{{> index/book_card}}`.
+///
+/// Release builds register the sources embedded at build time. Debug builds
+/// register the files on disk instead, and with the registry in dev mode
+/// Handlebars reads them again on every render, so template changes show up
+/// on the next request without a rebuild.
+pub fn register_templates(handlebars: &mut Handlebars) {
+    handlebars.register_helper("markdown", Box::new(markdown));
+    for template in TEMPLATES {
+        let registered = if cfg!(debug_assertions) {
+            handlebars.register_template_file(template.name, template.path)
+        } else {
+            handlebars.register_template_string(template.name, template.source)
+        };
+        registered
+            .unwrap_or_else(|e| panic!("Failed to register {} template: {}", template.name, e));
+    }
+}
+
+handlebars_helper!(markdown: |text: str| render_markdown(text));
+
+/// Renders story Markdown to HTML that is safe to put in a page: raw HTML is
+/// shown as text, and links and images may only point at http(s), mailto or
+/// relative URLs.
+pub fn render_markdown(text: &str) -> String {
+    let events = Parser::new_ext(text, Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES).map(
+        |event| match event {
+            Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Link {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => Event::Start(Tag::Image {
+                link_type,
+                dest_url: safe_url(dest_url),
+                title,
+                id,
+            }),
+            event => event,
+        },
+    );
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn safe_url(url: CowStr<'_>) -> CowStr<'_> {
+    let scheme = url
+        .split_once(':')
+        .map(|(scheme, _)| scheme)
+        .filter(|scheme| !scheme.contains(['/', '?', '#']));
+    match scheme.map(str::to_ascii_lowercase).as_deref() {
+        None | Some("http") | Some("https") | Some("mailto") => url,
+        Some(_) => CowStr::Borrowed("#"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_markdown() {
+        assert_eq!(
+            render_markdown("The lamp is *dark*.\n\n~~lit~~"),
+            "<p>The lamp is <em>dark</em>.</p>\n<p><del>lit</del></p>\n"
+        );
+    }
+
+    #[test]
+    fn shows_raw_html_as_text() {
+        assert_eq!(
+            render_markdown("Hi <script>alert(1)</script>"),
+            "<p>Hi &lt;script&gt;alert(1)&lt;/script&gt;</p>\n"
+        );
+    }
+
+    #[test]
+    fn only_keeps_safe_link_targets() {
+        assert_eq!(
+            render_markdown("[a](javascript:alert(1)) [b](https://x.org) [c](page?x=y:z)"),
+            "<p><a href=\"#\">a</a> <a href=\"https://x.org\">b</a> \
+             <a href=\"page?x=y:z\">c</a></p>\n"
+        );
+        assert_eq!(
+            render_markdown("![x](data:image/png;base64,AAAA)"),
+            "<p><img src=\"#\" alt=\"x\" /></p>\n"
+        );
+    }
+}